@@ -125,9 +125,11 @@ class Message:
         content (str): The content of the message.
         queue (Queue): The Queue object associated with the message (optional).
         message_uuid (str): The UUID of the message (optional).
+        receipt_handle (str): The receipt handle the message was last received with (optional).
 
     Methods:
         set_uuid(uuid: str): Sets the UUID of the message.
+        set_receipt_handle(receipt_handle: str): Sets the receipt handle of the message.
         set_queue(queue: Queue): Sets the Queue object associated with the message.
         delete(): Deletes the message from the associated queue.
     """
@@ -137,6 +139,7 @@ class Message:
         assert type(content) == str
         self.queue = None
         self.message_uuid = None
+        self.receipt_handle = None
         self.message_id = message_id
         self.content = content
         self.uuid = None
@@ -145,6 +148,10 @@ class Message:
         assert type(uuid) == str
         self.message_uuid = uuid
 
+    def set_receipt_handle(self, receipt_handle: str):
+        assert type(receipt_handle) == str
+        self.receipt_handle = receipt_handle
+
     def set_queue(self, queue: Queue):
         assert isinstance(queue, Queue)
         self.queue = queue
@@ -154,14 +161,17 @@ class Message:
         delete()
 
         Deletes the message from the associated queue as long as the message
-            has been retrieved.
+            has been retrieved and not received again since.
 
         Returns:
             SuccessResponse: A SuccessResponse object indicating a successful deletion.
         """
         assert self.queue is not None
-        assert self.message_uuid is not None
-        post_data = {"queueId": self.queue.queue_id, "messageUuid": self.message_uuid}
+        assert self.receipt_handle is not None
+        post_data = {
+            "queueId": self.queue.queue_id,
+            "receiptHandle": self.receipt_handle,
+        }
         r = requests.post(f"{self.queue.base_url}/message/delete", json=post_data)
         if r.status_code >= 400:
             raise ErrorResponse(
@@ -181,6 +191,7 @@ class MessageHandler:
     Methods:
         produce(messages: list[Message]): Publishes a list of messages to the associated queue.
         consume(): Consumes messages from the associated queue.
+        delete(messages: list[Message]): Deletes received messages from the associated queue.
     """
 
     def __init__(self, base_url: str, queue: Queue):
@@ -241,11 +252,38 @@ class MessageHandler:
         for message in r.json()["data"]:
             message_obj = Message(message["messageId"], message["content"])
             message_obj.set_uuid(message["uuid"])
+            message_obj.set_receipt_handle(message["receiptHandle"])
             message_obj.set_queue(self.queue)
             messages_received.append(message_obj)
 
         return messages_received
 
+    def delete(self, messages: list[Message]):
+        """
+        delete(messages: list[Message])
+
+        Deletes received messages from the associated queue in one request.
+
+        Args:
+            messages (list[Message]): A list of Message objects returned by consume().
+
+        Returns:
+            SuccessResponse: A SuccessResponse object with whether each message was deleted.
+        """
+        assert type(messages) == list
+        for message in messages:
+            assert isinstance(message, Message)
+            assert message.receipt_handle is not None
+
+        post_data = {
+            "queueId": self.queue.queue_id,
+            "receiptHandles": [m.receipt_handle for m in messages],
+        }
+        r = requests.post(f"{self.base_url}/message/delete/batch", json=post_data)
+        if r.status_code >= 400:
+            raise ErrorResponse(f"failed to delete messages. Response was {r.json()}")
+        return SuccessResponse(r.json())
+
 
 class Exchange:
     """
@@ -0,0 +1,106 @@
+use std::env;
+use std::io;
+use std::path::{self, Component, Path, PathBuf};
+use std::time::Duration;
+
+use crate::persistence::wal::FsyncPolicy;
+
+pub struct Config {
+    pub data_dir: PathBuf, // where the write-ahead log and snapshots are kept
+    pub fsync_policy: FsyncPolicy, // how eagerly the log is flushed to disk
+    pub snapshot_interval: Duration, // how often the log is compacted into a snapshot
+    pub key_file: Option<PathBuf>, // where the encryption keys are read from
+    pub keys: Option<String>, // encryption keys given inline instead of a key file
+    pub sweep_interval: Duration, // how often expired messages are removed
+}
+
+impl Config {
+    // EDI_DATA_DIR, EDI_FSYNC (always | batch | os), EDI_FSYNC_INTERVAL_MS,
+    // EDI_SNAPSHOT_INTERVAL_SECS, EDI_KEY_FILE, EDI_KEYS and EDI_SWEEP_INTERVAL_SECS
+    pub fn from_env() -> io::Result<Self> {
+        let data_dir = env::var("EDI_DATA_DIR").unwrap_or_else(|_| String::from("data"));
+        let interval = match env::var("EDI_FSYNC_INTERVAL_MS") {
+            Err(_) => 200,
+            Ok(s) => match s.parse::<u64>() {
+                Ok(ms) if ms > 0 => ms,
+                _ => return Err(invalid(format!("The fsync interval {} is invalid", s))),
+            },
+        };
+        let fsync_policy = match env::var("EDI_FSYNC").as_deref() {
+            Ok("always") => FsyncPolicy::Always,
+            Err(_) | Ok("batch") => FsyncPolicy::Batched(Duration::from_millis(interval)),
+            Ok("os") => FsyncPolicy::Os,
+            Ok(s) => return Err(invalid(format!("The fsync policy {} is invalid", s))),
+        };
+        let snapshot_interval = match env::var("EDI_SNAPSHOT_INTERVAL_SECS") {
+            Err(_) => 300,
+            Ok(s) => match s.parse::<u64>() {
+                Ok(secs) if secs > 0 => secs,
+                _ => return Err(invalid(format!("The snapshot interval {} is invalid", s))),
+            },
+        };
+        let sweep_interval = match env::var("EDI_SWEEP_INTERVAL_SECS") {
+            Err(_) => 1,
+            Ok(s) => match s.parse::<u64>() {
+                Ok(secs) if secs > 0 => secs,
+                _ => return Err(invalid(format!("The sweep interval {} is invalid", s))),
+            },
+        };
+        let keys = env::var("EDI_KEYS").ok();
+        let key_file = env::var("EDI_KEY_FILE").ok().map(PathBuf::from);
+        // anyone with a copy of the data directory could read every message if
+        // the keys were kept in it too
+        match (&keys, &key_file) {
+            (None, None) => {
+                return Err(invalid(String::from(
+                    "No encryption keys were configured, set EDI_KEYS or EDI_KEY_FILE",
+                )))
+            }
+            (None, Some(key_file)) => {
+                if resolve(key_file)?.starts_with(resolve(Path::new(&data_dir))?) {
+                    return Err(invalid(format!(
+                        "The key file {} must not be inside the data directory {}",
+                        key_file.display(),
+                        data_dir
+                    )));
+                }
+            }
+            (Some(_), Some(_)) => {
+                return Err(invalid(String::from(
+                    "Set either EDI_KEYS or EDI_KEY_FILE, not both",
+                )))
+            }
+            (Some(_), None) => (),
+        }
+        Ok(Config {
+            data_dir: PathBuf::from(data_dir),
+            fsync_policy,
+            snapshot_interval: Duration::from_secs(snapshot_interval),
+            key_file,
+            keys,
+            sweep_interval: Duration::from_secs(sweep_interval),
+        })
+    }
+}
+
+// where a path leads once symlinks are followed, for paths that may not exist
+// yet. The part of the path that does not exist is taken as written
+fn resolve(path: &Path) -> io::Result<PathBuf> {
+    if let Ok(resolved) = path.canonicalize() {
+        return Ok(resolved);
+    }
+    let absolute = path::absolute(path)?;
+    let parent = match absolute.parent() {
+        None => return Ok(absolute),
+        Some(parent) => resolve(parent)?,
+    };
+    match absolute.components().next_back() {
+        Some(Component::ParentDir) => Ok(parent.parent().unwrap_or(&parent).to_path_buf()),
+        Some(Component::Normal(name)) => Ok(parent.join(name)),
+        _ => Ok(parent),
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
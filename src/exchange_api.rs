@@ -1,42 +1,87 @@
 use std::collections::hash_map::Entry;
+use std::collections::BTreeMap;
 
 use actix_web::{web, HttpResponse};
-use request::{NewExchangeRequest, NewMessageRequest};
+use request::{NewExchangeRequest, NewMessage, NewMessageRequest};
 
 use crate::app_types::{AppState, JsonResponse};
+use crate::persistence::Record;
+use crate::queue_api::queue::MessageOptions;
 
-use exchange::{Exchange, ExchangeType};
-use request::ExchangeEntry;
+use binding::Binding;
+use exchange::{deliver, Exchange, ExchangeType};
+use request::{BindingRequest, ExchangeEntry, ExchangeRequest};
 
 use self::exchange::ExchangeToQueueError;
 
+pub(crate) mod binding;
 pub(crate) mod exchange;
+mod headers;
 mod request;
+mod topic;
 
 pub async fn new_exchange(
     data: web::Data<AppState>,
     post_data: web::Json<NewExchangeRequest>,
 ) -> HttpResponse {
-    let mut queues = data.get_queues().lock().await;
-    for queue_id in post_data.queue_ids.iter() {
-        match queues.entry(queue_id.to_owned()) {
-            Entry::Vacant(_) => {
-                return HttpResponse::BadRequest().json(JsonResponse::new(
-                    None::<String>,
-                    format!("No queue with id {} was found", queue_id),
-                ))
-            }
-            Entry::Occupied(_) => (),
+    // queueIds is a short way of binding queues without a key, which direct
+    // exchanges then bind under the queue's id. Topic and headers bindings
+    // without a pattern or arguments would match every message
+    if !post_data.queue_ids.is_empty()
+        && !matches!(
+            post_data.exchange_type,
+            ExchangeType::FANOUT | ExchangeType::DIRECT
+        )
+    {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!(
+                "{:?} exchanges bind queues with bindings, not queueIds",
+                post_data.exchange_type
+            ),
+        ));
+    }
+    let requested = post_data
+        .queue_ids
+        .iter()
+        .map(|queue_id| Binding {
+            queue_id: queue_id.to_owned(),
+            binding_key: None,
+            arguments: BTreeMap::new(),
+        })
+        .chain(post_data.bindings.iter().cloned());
+    let mut bindings = vec![];
+    for binding in requested {
+        match binding.normalize(&post_data.exchange_type) {
+            Ok(b) => bindings.push(b),
+            Err(e) => return HttpResponse::BadRequest().json(JsonResponse::new(None::<String>, e)),
+        }
+    }
+    let queues = data.get_queues().read().await;
+    for binding in bindings.iter() {
+        if !queues.contains_key(&binding.queue_id) {
+            return HttpResponse::BadRequest().json(JsonResponse::new(
+                None::<String>,
+                format!("No queue with id {} was found", binding.queue_id),
+            ));
         }
     }
 
     let mut exchanges = data.get_exchanges().lock().await;
     match exchanges.entry(post_data.id.to_owned()) {
         Entry::Vacant(_) => {
-            let queue_ids = post_data.queue_ids.to_owned();
             let new_exchange =
-                Exchange::new(post_data.id.to_owned(), queue_ids, &post_data.exchange_type);
+                Exchange::new(post_data.id.to_owned(), bindings, &post_data.exchange_type);
             let exchange_uuid = new_exchange.uuid.to_string();
+            let record = Record::NewExchange {
+                exchange: new_exchange.clone(),
+            };
+            if data.get_wal().append(&record).is_err() {
+                return HttpResponse::InternalServerError().json(JsonResponse::new(
+                    None::<String>,
+                    "Something went wrong. Please try again.",
+                ));
+            }
             exchanges.insert(post_data.id.to_owned(), new_exchange);
             HttpResponse::Accepted().json(JsonResponse::new(exchange_uuid, None::<String>))
         }
@@ -49,26 +94,92 @@ pub async fn new_exchange(
 
 pub async fn list_exchanges(data: web::Data<AppState>) -> HttpResponse {
     let exchanges = data.get_exchanges().lock().await;
-    let mut vec_of_exchanges = vec![];
-    for exchange in exchanges.values() {
-        vec_of_exchanges.push(ExchangeEntry {
-            id: exchange.id.clone(),
-            queue_ids: exchange.queue_ids.clone(),
-            exchange_type: match exchange.exchange_type {
-                ExchangeType::FANOUT => ExchangeType::FANOUT,
-                ExchangeType::ID => ExchangeType::ID,
-            },
-        })
-    }
+    let vec_of_exchanges = exchanges
+        .values()
+        .map(ExchangeEntry::new)
+        .collect::<Vec<ExchangeEntry>>();
     HttpResponse::Accepted().json(JsonResponse::new(vec_of_exchanges, None::<String>))
 }
 
-pub async fn add_message_to_exchange(
+pub async fn describe_exchange(
     data: web::Data<AppState>,
-    post_data: web::Json<NewMessageRequest>,
+    query_data: web::Query<ExchangeRequest>,
+) -> HttpResponse {
+    let exchanges = data.get_exchanges().lock().await;
+    match exchanges.get(&query_data.exchange_id) {
+        None => HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!("No exchange with id {} was found", query_data.exchange_id),
+        )),
+        Some(exchange) => HttpResponse::Accepted().json(JsonResponse::new(
+            ExchangeEntry::new(exchange),
+            None::<String>,
+        )),
+    }
+}
+
+pub async fn bind_queue(
+    data: web::Data<AppState>,
+    post_data: web::Json<BindingRequest>,
 ) -> HttpResponse {
     let exchange_id = &post_data.exchange_id;
+    // the queue map stays locked so the queue cannot be deleted before it is bound
+    let queues = data.get_queues().read().await;
+    if !queues.contains_key(&post_data.binding.queue_id) {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!("No queue with id {} was found", post_data.binding.queue_id),
+        ));
+    }
+    let mut exchanges = data.get_exchanges().lock().await;
+    let exchange = match exchanges.get_mut(exchange_id) {
+        None => {
+            return HttpResponse::BadRequest().json(JsonResponse::new(
+                None::<String>,
+                format!("No exchange with id {} was found", exchange_id),
+            ))
+        }
+        Some(e) => e,
+    };
+    let binding = match post_data.binding.clone().normalize(&exchange.exchange_type) {
+        Ok(b) => b,
+        Err(e) => return HttpResponse::BadRequest().json(JsonResponse::new(None::<String>, e)),
+    };
+    if exchange.get_bindings().contains(&binding) {
+        return HttpResponse::Conflict().json(JsonResponse::new(
+            None::<String>,
+            format!(
+                "The queue {} is already bound to {} that way",
+                binding.queue_id, exchange_id
+            ),
+        ));
+    }
 
+    let record = Record::BindQueue {
+        exchange_id: exchange_id.to_owned(),
+        binding: binding.clone(),
+    };
+    if data.get_wal().append(&record).is_err() {
+        return HttpResponse::InternalServerError().json(JsonResponse::new(
+            None::<String>,
+            "Something went wrong. Please try again.",
+        ));
+    }
+    exchange.bind(binding);
+    HttpResponse::Accepted().json(JsonResponse::new(
+        format!(
+            "Successfully bound queue {} to {}",
+            post_data.binding.queue_id, exchange_id
+        ),
+        None::<String>,
+    ))
+}
+
+pub async fn unbind_queue(
+    data: web::Data<AppState>,
+    post_data: web::Json<BindingRequest>,
+) -> HttpResponse {
+    let exchange_id = &post_data.exchange_id;
     let mut exchanges = data.get_exchanges().lock().await;
     let exchange = match exchanges.get_mut(exchange_id) {
         None => {
@@ -77,18 +188,141 @@ pub async fn add_message_to_exchange(
                 format!("No exchange with id {} was found", exchange_id),
             ))
         }
-        Some(q) => q,
+        Some(e) => e,
+    };
+    // normalized the way it was when it was bound
+    let binding = post_data
+        .binding
+        .clone()
+        .normalize(&exchange.exchange_type)
+        .unwrap_or_else(|_| post_data.binding.clone());
+    if !exchange.get_bindings().contains(&binding) {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!(
+                "The queue {} is not bound to {} that way",
+                binding.queue_id, exchange_id
+            ),
+        ));
+    }
+
+    let record = Record::UnbindQueue {
+        exchange_id: exchange_id.to_owned(),
+        binding: binding.clone(),
+    };
+    if data.get_wal().append(&record).is_err() {
+        return HttpResponse::InternalServerError().json(JsonResponse::new(
+            None::<String>,
+            "Something went wrong. Please try again.",
+        ));
+    }
+    exchange.unbind(&binding);
+    HttpResponse::Accepted().json(JsonResponse::new(
+        format!(
+            "Successfully unbound queue {} from {}",
+            binding.queue_id, exchange_id
+        ),
+        None::<String>,
+    ))
+}
+
+// the bound queues and their messages are left as they are
+pub async fn delete_exchange(
+    data: web::Data<AppState>,
+    post_data: web::Json<ExchangeRequest>,
+) -> HttpResponse {
+    let exchange_id = &post_data.exchange_id;
+    let mut exchanges = data.get_exchanges().lock().await;
+    if !exchanges.contains_key(exchange_id) {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!("No exchange with id {} was found", exchange_id),
+        ));
+    }
+
+    let record = Record::DeleteExchange {
+        exchange_id: exchange_id.to_owned(),
     };
+    if data.get_wal().append(&record).is_err() {
+        return HttpResponse::InternalServerError().json(JsonResponse::new(
+            None::<String>,
+            "Something went wrong. Please try again.",
+        ));
+    }
+    exchanges.remove(exchange_id);
+    HttpResponse::Accepted().json(JsonResponse::new(
+        format!("Successfully deleted exchange {}", exchange_id),
+        None::<String>,
+    ))
+}
+
+pub async fn add_message_to_exchange(
+    data: web::Data<AppState>,
+    post_data: web::Json<NewMessageRequest>,
+) -> HttpResponse {
+    let exchange_id = &post_data.exchange_id;
+
     let messages_to_add = &post_data.messages;
+    if messages_to_add.iter().any(|m| m.ttl_seconds == Some(0)) {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            "The time to live 0 is invalid",
+        ));
+    }
+    // the messages are routed while the exchange is locked, but the lock is
+    // let go before the queues are locked since other handlers take the queue
+    // map lock before the exchanges lock
+    let routes = {
+        let exchanges = data.get_exchanges().lock().await;
+        let exchange = match exchanges.get(exchange_id) {
+            None => {
+                return HttpResponse::BadRequest().json(JsonResponse::new(
+                    None::<String>,
+                    format!("No exchange with id {} was found", exchange_id),
+                ))
+            }
+            Some(e) => e,
+        };
+        messages_to_add
+            .iter()
+            .map(|m| exchange.route(&m.message_id, m.routing_key.as_deref(), &m.attributes))
+            .collect::<Result<Vec<Vec<String>>, ExchangeToQueueError>>()
+    };
+    let routes = match routes {
+        Ok(r) => r,
+        Err(e) => {
+            return HttpResponse::BadRequest()
+                .json(JsonResponse::new(None::<String>, e.to_string()))
+        }
+    };
+    if let Err(e) = check_routes(messages_to_add, &routes, &data).await {
+        return HttpResponse::BadRequest().json(JsonResponse::new(None::<String>, e.to_string()));
+    }
     let mut messages_to_send = vec![];
-    for message in messages_to_add.iter() {
-        let id = message.message_id.to_owned();
-        let content = message.content.to_owned();
-        let message_added = exchange.dispatch(id, content, &data).await;
+    for (message, queue_ids) in messages_to_add.iter().zip(routes.iter()) {
+        let options = MessageOptions {
+            delay_seconds: message.delay_seconds,
+            ttl_seconds: message.ttl_seconds,
+            group_id: message.group_id.clone(),
+            deduplication_id: message.deduplication_id.clone(),
+            priority: message.priority,
+            attributes: message.attributes.clone(),
+        };
+        let message_added = deliver(
+            queue_ids,
+            &message.message_id,
+            &message.content,
+            &options,
+            &data,
+        )
+        .await;
         match message_added {
             Ok(v) => messages_to_send.extend(v),
             Err(e) => match e {
-                ExchangeToQueueError::NoMatchingQueueError(_) => {
+                ExchangeToQueueError::NoMatchingQueueError(_)
+                | ExchangeToQueueError::Unroutable(_)
+                | ExchangeToQueueError::MissingGroupId(_)
+                | ExchangeToQueueError::MissingRoutingKey => {
                     return HttpResponse::BadRequest()
                         .json(JsonResponse::new(None::<String>, e.to_string()))
                 }
@@ -102,3 +336,37 @@ pub async fn add_message_to_exchange(
 
     HttpResponse::Accepted().json(JsonResponse::new(messages_to_send, None::<String>))
 }
+
+// checked before anything is delivered so the batch is not half sent: every
+// queue a message is routed to has to exist, and FIFO queues need a group id
+async fn check_routes(
+    messages: &[NewMessage],
+    routes: &[Vec<String>],
+    data: &AppState,
+) -> Result<(), ExchangeToQueueError> {
+    let mut fifo = BTreeMap::new();
+    for queue_id in routes.iter().flatten() {
+        if fifo.contains_key(queue_id) {
+            continue;
+        }
+        let queue = match data.get_queue(queue_id).await {
+            None => {
+                return Err(ExchangeToQueueError::NoMatchingQueueError(
+                    queue_id.to_owned(),
+                ))
+            }
+            Some(q) => q,
+        };
+        let is_fifo = queue.lock().await.get_config().fifo;
+        fifo.insert(queue_id, is_fifo);
+    }
+    for (message, queue_ids) in messages.iter().zip(routes.iter()) {
+        if message.group_id.is_some() {
+            continue;
+        }
+        if let Some(queue_id) = queue_ids.iter().find(|q| fifo[q]) {
+            return Err(ExchangeToQueueError::MissingGroupId(queue_id.to_owned()));
+        }
+    }
+    Ok(())
+}
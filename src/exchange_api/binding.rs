@@ -0,0 +1,46 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::exchange::ExchangeType;
+use super::headers::validate_arguments;
+use super::topic::validate_pattern;
+
+// binds a queue to an exchange. What the key means depends on the exchange:
+// direct exchanges send a message to the queues bound with its routing key,
+// topic exchanges treat the key as a pattern and fanout and headers exchanges
+// ignore it. Headers exchanges match on the arguments instead
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Binding {
+    pub queue_id: String,
+    pub binding_key: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub arguments: BTreeMap<String, String>, // settings of the binding, kept as given
+}
+
+impl Binding {
+    // fills in what the exchange needs and checks the rest. Direct bindings
+    // without a key are bound under the queue's id
+    pub fn normalize(mut self, exchange_type: &ExchangeType) -> Result<Self, String> {
+        match exchange_type {
+            ExchangeType::FANOUT => (),
+            ExchangeType::DIRECT => {
+                if self.binding_key.is_none() {
+                    self.binding_key = Some(self.queue_id.to_owned());
+                }
+            }
+            ExchangeType::TOPIC => match &self.binding_key {
+                None => {
+                    return Err(format!(
+                        "The binding of queue {} needs a bindingKey",
+                        self.queue_id
+                    ))
+                }
+                Some(pattern) => validate_pattern(pattern)?,
+            },
+            ExchangeType::HEADERS => validate_arguments(&self.arguments)?,
+        }
+        Ok(self)
+    }
+}
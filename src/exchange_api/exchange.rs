@@ -1,118 +1,269 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
 use actix_web::web;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::app_types::AppState;
+use crate::queue_api::queue::{MessageOptions, QueueError};
+
+use super::binding::Binding;
+use super::headers::HeadersMatcher;
+use super::topic::TopicTrie;
 
 pub enum ExchangeToQueueError {
     NoMatchingQueueError(String),
+    Unroutable(String),     // no queue is bound with the routing key
+    MissingGroupId(String), // the queue is a FIFO queue and the message has no group id
+    MissingRoutingKey,      // topic exchanges route on the routing key
     UnableToAddError,
 }
 
-impl ExchangeToQueueError {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for ExchangeToQueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ExchangeToQueueError::NoMatchingQueueError(s) => {
-                format!("No queue with id {} was found", s)
+                write!(f, "No queue with id {} was found", s)
+            }
+            ExchangeToQueueError::MissingGroupId(s) => {
+                write!(
+                    f,
+                    "Queue {} is a FIFO queue, messages sent to it need a groupId",
+                    s
+                )
+            }
+            ExchangeToQueueError::Unroutable(s) => {
+                write!(f, "No queue is bound with the key {}", s)
+            }
+            ExchangeToQueueError::MissingRoutingKey => {
+                write!(f, "Messages sent to a TOPIC exchange need a routingKey")
             }
             ExchangeToQueueError::UnableToAddError => {
-                String::from("Something went wrong. Please try again.")
+                write!(f, "Something went wrong. Please try again.")
             }
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::upper_case_acronyms)] // the variant names are the wire format
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ExchangeType {
     FANOUT, // Fanout pushes message to all bound keys
-    ID,     // Id pushes message to queues with particular id
+    #[serde(alias = "ID")]
+    DIRECT, // Direct pushes message to queues bound with its routing key
+    TOPIC,  // Topic pushes message to queues bound with a pattern matching its routing key
+    HEADERS, // Headers pushes message to queues bound with arguments matching its attributes
+}
+
+// how an exchange finds the queues of a routing key, built from its bindings
+#[derive(Clone)]
+enum Router {
+    Fanout(Vec<String>),
+    Direct(HashMap<String, Vec<String>>),
+    Topic(TopicTrie),
+    Headers(Vec<HeadersMatcher>),
 }
 
+// an exchange without bindings routes nothing, whatever its type
+impl Default for Router {
+    fn default() -> Self {
+        Router::Fanout(vec![])
+    }
+}
+
+impl Router {
+    fn new(exchange_type: &ExchangeType, bindings: &[Binding]) -> Self {
+        let keyed = bindings
+            .iter()
+            .filter_map(|b| b.binding_key.as_ref().map(|key| (key, &b.queue_id)));
+        match exchange_type {
+            ExchangeType::FANOUT => {
+                let mut queue_ids: Vec<String> = vec![];
+                for binding in bindings.iter() {
+                    if !queue_ids.contains(&binding.queue_id) {
+                        queue_ids.push(binding.queue_id.to_owned());
+                    }
+                }
+                Router::Fanout(queue_ids)
+            }
+            ExchangeType::DIRECT => {
+                let mut by_key: HashMap<String, Vec<String>> = HashMap::new();
+                for (key, queue_id) in keyed {
+                    let queue_ids = by_key.entry(key.to_owned()).or_default();
+                    if !queue_ids.contains(queue_id) {
+                        queue_ids.push(queue_id.to_owned());
+                    }
+                }
+                Router::Direct(by_key)
+            }
+            ExchangeType::TOPIC => {
+                let mut trie = TopicTrie::default();
+                for (pattern, queue_id) in keyed {
+                    trie.insert(pattern, queue_id);
+                }
+                Router::Topic(trie)
+            }
+            ExchangeType::HEADERS => Router::Headers(
+                bindings
+                    .iter()
+                    .map(|b| HeadersMatcher::new(&b.queue_id, &b.arguments))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Exchange {
     pub id: String,                  // the exchange id
     pub uuid: Uuid,                  // inner generated uuid for resource
-    pub queue_ids: Vec<String>,      // the queues that are bound to the exchange
     pub exchange_type: ExchangeType, // what to do with messages
+    bindings: Vec<Binding>,          // the queues that are bound to the exchange
+    #[serde(skip)]
+    router: Router, // rebuilt from the bindings when the exchange is loaded
 }
 
 impl Exchange {
-    pub fn new(id: String, queue_ids: Vec<String>, exchange_type: &ExchangeType) -> Self {
+    pub fn new(id: String, bindings: Vec<Binding>, exchange_type: &ExchangeType) -> Self {
+        let mut unique: Vec<Binding> = vec![];
+        for binding in bindings {
+            if !unique.contains(&binding) {
+                unique.push(binding);
+            }
+        }
         Exchange {
             id,
             uuid: Uuid::new_v4(),
-            queue_ids,
-            exchange_type: match exchange_type {
-                ExchangeType::FANOUT => ExchangeType::FANOUT,
-                ExchangeType::ID => ExchangeType::ID,
-            },
+            router: Router::new(exchange_type, &unique),
+            exchange_type: exchange_type.clone(),
+            bindings: unique,
         }
     }
 
-    pub async fn dispatch(
-        &self,
-        id: String,
-        content: String,
-        app_data: &web::Data<AppState>,
-    ) -> Result<Vec<String>, ExchangeToQueueError> {
-        match self.exchange_type {
-            ExchangeType::ID => self.id_dispatch(id, content, app_data).await,
-            ExchangeType::FANOUT => self.fanout_dispatch(id, content, app_data).await,
+    // the router is not logged, so exchanges read back from a snapshot or the
+    // log route nothing until this is called
+    pub fn rebuild_router(&mut self) {
+        self.router = Router::new(&self.exchange_type, &self.bindings);
+    }
+
+    pub fn get_bindings(&self) -> &[Binding] {
+        &self.bindings
+    }
+
+    // every bound queue once, in the order they were first bound
+    pub fn get_queue_ids(&self) -> Vec<String> {
+        let mut queue_ids: Vec<String> = vec![];
+        for binding in self.bindings.iter() {
+            if !queue_ids.contains(&binding.queue_id) {
+                queue_ids.push(binding.queue_id.to_owned());
+            }
+        }
+        queue_ids
+    }
+
+    pub fn is_bound(&self, queue_id: &str) -> bool {
+        self.bindings.iter().any(|b| b.queue_id == queue_id)
+    }
+
+    // returns false if the queue is already bound that way
+    pub fn bind(&mut self, binding: Binding) -> bool {
+        if self.bindings.contains(&binding) {
+            return false;
+        }
+        self.bindings.push(binding);
+        self.rebuild_router();
+        true
+    }
+
+    // returns false if there is no such binding
+    pub fn unbind(&mut self, binding: &Binding) -> bool {
+        let count = self.bindings.len();
+        self.bindings.retain(|b| b != binding);
+        if self.bindings.len() == count {
+            return false;
         }
+        self.rebuild_router();
+        true
     }
 
-    async fn id_dispatch(
+    // drops every binding of a queue
+    pub fn unbind_queue(&mut self, queue_id: &str) {
+        self.bindings.retain(|b| b.queue_id != queue_id);
+        self.rebuild_router();
+    }
+
+    // the queues a message is sent to. Direct exchanges route on the message
+    // id when there is no routing key. Messages sent to a topic or headers
+    // exchange that match no binding go nowhere
+    pub fn route(
         &self,
-        id: String,
-        content: String,
-        app_data: &web::Data<AppState>,
+        id: &str,
+        routing_key: Option<&str>,
+        attributes: &BTreeMap<String, String>,
     ) -> Result<Vec<String>, ExchangeToQueueError> {
-        let cipher = app_data.get_cipher().lock().await;
-        for queue_id in self.queue_ids.iter() {
-            // messages are sent to queues with same id as message id
-            if *queue_id == id {
-                let mut queues = app_data.get_queues().lock().await;
-                let queue: &mut crate::queue_api::queue::Queue = match queues.get_mut(queue_id) {
-                    None => {
-                        return Err(ExchangeToQueueError::NoMatchingQueueError(
-                            queue_id.to_owned(),
-                        ));
+        match &self.router {
+            Router::Fanout(queue_ids) => Ok(queue_ids.clone()),
+            Router::Direct(by_key) => {
+                let key = routing_key.unwrap_or(id);
+                match by_key.get(key) {
+                    None => Err(ExchangeToQueueError::Unroutable(key.to_owned())),
+                    Some(queue_ids) => Ok(queue_ids.clone()),
+                }
+            }
+            Router::Topic(trie) => match routing_key {
+                None => Err(ExchangeToQueueError::MissingRoutingKey),
+                Some(routing_key) => {
+                    let mut queue_ids = trie.matches(routing_key).into_iter().collect::<Vec<_>>();
+                    queue_ids.sort();
+                    Ok(queue_ids)
+                }
+            },
+            Router::Headers(matchers) => {
+                let mut queue_ids: Vec<String> = vec![];
+                for matcher in matchers.iter() {
+                    if !queue_ids.contains(&matcher.queue_id) && matcher.matches(attributes) {
+                        queue_ids.push(matcher.queue_id.to_owned());
                     }
-                    Some(q) => q,
-                };
-                let message = queue.add_to_queue(&cipher, id, content);
-                match message {
-                    Ok(m) => return Ok(vec![m]),
-                    Err(_) => return Err(ExchangeToQueueError::UnableToAddError),
                 }
+                Ok(queue_ids)
             }
         }
-        return Err(ExchangeToQueueError::NoMatchingQueueError(id));
     }
+}
 
-    async fn fanout_dispatch(
-        &self,
-        id: String,
-        content: String,
-        app_data: &web::Data<AppState>,
-    ) -> Result<Vec<String>, ExchangeToQueueError> {
-        let mut messages_produced = vec![];
-        let cipher = app_data.get_cipher().lock().await;
-        for queue_id in self.queue_ids.iter() {
-            let mut queues = app_data.get_queues().lock().await;
-            let queue = match queues.get_mut(queue_id) {
-                None => {
-                    return Err(ExchangeToQueueError::NoMatchingQueueError(
-                        queue_id.to_owned(),
-                    ));
-                }
-                Some(q) => q,
-            };
-            let message = match queue.add_to_queue(&cipher, id.to_owned(), content.to_owned()) {
-                Ok(m) => m,
-                Err(_) => return Err(ExchangeToQueueError::UnableToAddError),
-            };
-            messages_produced.push(message);
-        }
-        Ok(messages_produced)
+// adds a copy of the message to each of the queues it was routed to
+pub async fn deliver(
+    queue_ids: &[String],
+    id: &str,
+    content: &str,
+    options: &MessageOptions,
+    app_data: &web::Data<AppState>,
+) -> Result<Vec<String>, ExchangeToQueueError> {
+    let mut messages_produced = vec![];
+    for queue_id in queue_ids.iter() {
+        let queue = match app_data.get_queue(queue_id).await {
+            None => {
+                return Err(ExchangeToQueueError::NoMatchingQueueError(
+                    queue_id.to_owned(),
+                ));
+            }
+            Some(q) => q,
+        };
+        let message = match queue.lock().await.add_to_queue(
+            app_data.get_keys(),
+            app_data.get_wal(),
+            id.to_owned(),
+            content.to_owned(),
+            options,
+        ) {
+            Ok(m) => m,
+            Err(QueueError::MissingGroupId) => {
+                return Err(ExchangeToQueueError::MissingGroupId(queue_id.to_owned()))
+            }
+            Err(_) => return Err(ExchangeToQueueError::UnableToAddError),
+        };
+        messages_produced.push(message);
     }
+    Ok(messages_produced)
 }
@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+
+// the binding argument choosing whether all or any of the other arguments
+// have to match a message's attributes
+pub const MATCH_ARGUMENT: &str = "x-match";
+
+pub fn validate_arguments(arguments: &BTreeMap<String, String>) -> Result<(), String> {
+    match arguments.get(MATCH_ARGUMENT).map(|m| m.as_str()) {
+        None | Some("all") | Some("any") => Ok(()),
+        Some(m) => Err(format!(
+            "The {} argument {} is invalid, it must be all or any",
+            MATCH_ARGUMENT, m
+        )),
+    }
+}
+
+// a headers binding. Every argument other than `x-match` is an attribute the
+// message has to carry with the same value
+#[derive(Debug, Clone)]
+pub struct HeadersMatcher {
+    pub queue_id: String,
+    match_any: bool,
+    predicates: Vec<(String, String)>,
+}
+
+impl HeadersMatcher {
+    pub fn new(queue_id: &str, arguments: &BTreeMap<String, String>) -> Self {
+        HeadersMatcher {
+            queue_id: queue_id.to_owned(),
+            match_any: arguments.get(MATCH_ARGUMENT).is_some_and(|m| m == "any"),
+            predicates: arguments
+                .iter()
+                .filter(|(name, _)| *name != MATCH_ARGUMENT)
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+        }
+    }
+
+    // a binding with no predicates matches every message with `all` and none
+    // with `any`
+    pub fn matches(&self, attributes: &BTreeMap<String, String>) -> bool {
+        let mut results = self
+            .predicates
+            .iter()
+            .map(|(name, value)| attributes.get(name) == Some(value));
+        if self.match_any {
+            results.any(|matched| matched)
+        } else {
+            results.all(|matched| matched)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn all_needs_every_predicate_to_match() {
+        let matcher = HeadersMatcher::new("q", &map(&[("a", "1"), ("b", "2")]));
+        assert!(matcher.matches(&map(&[("a", "1"), ("b", "2"), ("c", "3")])));
+        assert!(!matcher.matches(&map(&[("a", "1")])));
+        assert!(!matcher.matches(&map(&[("a", "1"), ("b", "3")])));
+    }
+
+    #[test]
+    fn any_needs_one_predicate_to_match() {
+        let matcher = HeadersMatcher::new("q", &map(&[("x-match", "any"), ("a", "1"), ("b", "2")]));
+        assert!(matcher.matches(&map(&[("b", "2")])));
+        assert!(!matcher.matches(&map(&[("a", "2"), ("b", "1")])));
+        assert!(!matcher.matches(&map(&[])));
+    }
+
+    #[test]
+    fn x_match_is_not_a_predicate() {
+        let matcher = HeadersMatcher::new("q", &map(&[("x-match", "all"), ("a", "1")]));
+        assert!(matcher.matches(&map(&[("a", "1")])));
+    }
+
+    #[test]
+    fn no_predicates_match_everything_with_all_and_nothing_with_any() {
+        let attributes = map(&[("a", "1")]);
+        assert!(HeadersMatcher::new("q", &map(&[])).matches(&attributes));
+        assert!(HeadersMatcher::new("q", &map(&[("x-match", "all")])).matches(&attributes));
+        assert!(!HeadersMatcher::new("q", &map(&[("x-match", "any")])).matches(&attributes));
+    }
+
+    #[test]
+    fn validate_arguments_only_accepts_all_or_any() {
+        assert!(validate_arguments(&map(&[])).is_ok());
+        assert!(validate_arguments(&map(&[("x-match", "all"), ("a", "1")])).is_ok());
+        assert!(validate_arguments(&map(&[("x-match", "any")])).is_ok());
+        assert!(validate_arguments(&map(&[("x-match", "ALL")])).is_err());
+        assert!(validate_arguments(&map(&[("x-match", "")])).is_err());
+    }
+}
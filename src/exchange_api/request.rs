@@ -1,23 +1,58 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use super::exchange::ExchangeType;
+use super::binding::Binding;
+use super::exchange::{Exchange, ExchangeType};
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct NewExchangeRequest {
     pub id: String,
+    #[serde(default)]
     pub queue_ids: Vec<String>,
     #[serde(alias = "name")]
     pub exchange_type: ExchangeType,
+    #[serde(default)]
+    pub bindings: Vec<Binding>,
 }
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ExchangeEntry {
     pub id: String,
+    pub uuid: Uuid,
     pub queue_ids: Vec<String>,
     #[serde(alias = "name")]
     pub exchange_type: ExchangeType,
+    pub bindings: Vec<Binding>,
+}
+
+impl ExchangeEntry {
+    pub fn new(exchange: &Exchange) -> Self {
+        ExchangeEntry {
+            id: exchange.id.to_owned(),
+            uuid: exchange.uuid,
+            queue_ids: exchange.get_queue_ids(),
+            exchange_type: exchange.exchange_type.clone(),
+            bindings: exchange.get_bindings().to_vec(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExchangeRequest {
+    pub exchange_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BindingRequest {
+    pub exchange_id: String,
+    #[serde(flatten)]
+    pub binding: Binding,
 }
 
 #[derive(Deserialize)]
@@ -25,6 +60,14 @@ pub struct ExchangeEntry {
 pub struct NewMessage {
     pub message_id: String,
     pub content: String,
+    pub delay_seconds: Option<u32>,
+    pub ttl_seconds: Option<u32>,
+    pub group_id: Option<String>,
+    pub deduplication_id: Option<String>,
+    pub priority: Option<u8>,
+    pub routing_key: Option<String>, // what direct and topic exchanges route on
+    #[serde(default)]
+    pub attributes: BTreeMap<String, String>, // what headers exchanges route on
 }
 
 #[derive(Deserialize)]
@@ -0,0 +1,171 @@
+use std::collections::{HashMap, HashSet};
+
+// patterns and routing keys are words separated by dots. In a pattern `*`
+// stands for exactly one word and `#` for zero or more
+pub fn validate_pattern(pattern: &str) -> Result<(), String> {
+    let valid = pattern
+        .split('.')
+        .all(|word| !word.is_empty() && (word == "*" || word == "#" || !word.contains(['*', '#'])));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("The pattern {} is invalid", pattern))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    star: Option<Box<Node>>,
+    hash: Option<Box<Node>>,
+    queue_ids: HashSet<String>, // queues bound with a pattern ending here
+}
+
+// the bindings of a topic exchange, kept in a trie of their pattern words so
+// matching a routing key only walks the branches that can match it instead
+// of comparing it against every pattern
+#[derive(Debug, Clone, Default)]
+pub struct TopicTrie {
+    root: Node,
+}
+
+impl TopicTrie {
+    pub fn insert(&mut self, pattern: &str, queue_id: &str) {
+        let mut node = &mut self.root;
+        for word in pattern.split('.') {
+            node = match word {
+                "*" => node.star.get_or_insert_with(Default::default),
+                "#" => node.hash.get_or_insert_with(Default::default),
+                _ => node.children.entry(word.to_owned()).or_default(),
+            };
+        }
+        node.queue_ids.insert(queue_id.to_owned());
+    }
+
+    // the queues bound with a pattern matching `routing_key`, in no
+    // particular order. An empty routing key has no words, so only patterns
+    // made of `#` match it
+    pub fn matches(&self, routing_key: &str) -> HashSet<String> {
+        let words = match routing_key {
+            "" => vec![],
+            _ => routing_key.split('.').collect::<Vec<&str>>(),
+        };
+        let mut matched = HashSet::new();
+        let mut visited = HashSet::new();
+        visit(&self.root, &words, 0, &mut visited, &mut matched);
+        matched
+    }
+}
+
+// matches words[i..] against the patterns below `node`. `#` can swallow any
+// number of words, so the same node can be reached at the same word more than
+// once and `visited` keeps that from being walked again
+fn visit(
+    node: &Node,
+    words: &[&str],
+    i: usize,
+    visited: &mut HashSet<(*const Node, usize)>,
+    matched: &mut HashSet<String>,
+) {
+    if !visited.insert((node as *const Node, i)) {
+        return;
+    }
+    if let Some(hash) = &node.hash {
+        for j in i..=words.len() {
+            visit(hash, words, j, visited, matched);
+        }
+    }
+    if i == words.len() {
+        matched.extend(node.queue_ids.iter().cloned());
+        return;
+    }
+    if let Some(child) = node.children.get(words[i]) {
+        visit(child, words, i + 1, visited, matched);
+    }
+    if let Some(star) = &node.star {
+        visit(star, words, i + 1, visited, matched);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie(bindings: &[(&str, &str)]) -> TopicTrie {
+        let mut trie = TopicTrie::default();
+        for (pattern, queue_id) in bindings {
+            trie.insert(pattern, queue_id);
+        }
+        trie
+    }
+
+    fn matched(trie: &TopicTrie, routing_key: &str) -> Vec<String> {
+        let mut queue_ids = trie.matches(routing_key).into_iter().collect::<Vec<_>>();
+        queue_ids.sort();
+        queue_ids
+    }
+
+    #[test]
+    fn star_matches_exactly_one_word() {
+        let trie = trie(&[("a.*", "q")]);
+        assert_eq!(matched(&trie, "a.b"), ["q"]);
+        assert!(matched(&trie, "a").is_empty());
+        assert!(matched(&trie, "a.b.c").is_empty());
+        assert!(matched(&trie, "b.a").is_empty());
+    }
+
+    #[test]
+    fn hash_matches_zero_or_more_words() {
+        let trie = trie(&[("a.#", "q")]);
+        assert_eq!(matched(&trie, "a"), ["q"]);
+        assert_eq!(matched(&trie, "a.b"), ["q"]);
+        assert_eq!(matched(&trie, "a.b.c"), ["q"]);
+        assert!(matched(&trie, "b.a").is_empty());
+    }
+
+    #[test]
+    fn words_between_wildcards_must_line_up() {
+        let trie = trie(&[("a.*.c", "star"), ("a.#.c", "hash")]);
+        assert_eq!(matched(&trie, "a.b.c"), ["hash", "star"]);
+        assert_eq!(matched(&trie, "a.c"), ["hash"]);
+        assert_eq!(matched(&trie, "a.b.b.c"), ["hash"]);
+        assert!(matched(&trie, "a.b.c.d").is_empty());
+    }
+
+    #[test]
+    fn consecutive_hashes_match_anything() {
+        let trie = trie(&[("#.#", "q")]);
+        assert_eq!(matched(&trie, "a"), ["q"]);
+        assert_eq!(matched(&trie, "a.b.c"), ["q"]);
+        assert_eq!(matched(&trie, ""), ["q"]);
+    }
+
+    #[test]
+    fn a_queue_matched_by_several_patterns_is_returned_once() {
+        let trie = trie(&[
+            ("a.*", "q"),
+            ("a.#", "q"),
+            ("#", "q"),
+            ("a.b", "q"),
+            ("a.b", "r"),
+        ]);
+        assert_eq!(matched(&trie, "a.b"), ["q", "r"]);
+        assert_eq!(matched(&trie, "c"), ["q"]);
+    }
+
+    #[test]
+    fn an_empty_routing_key_only_matches_hashes() {
+        let trie = trie(&[("#", "hash"), ("*", "star"), ("a.#", "prefixed")]);
+        assert_eq!(matched(&trie, ""), ["hash"]);
+    }
+
+    #[test]
+    fn validate_pattern_rejects_partial_wildcards_and_empty_words() {
+        for pattern in ["a.*", "#", "a.#.b", "a.b"] {
+            assert!(validate_pattern(pattern).is_ok(), "{}", pattern);
+        }
+        for pattern in ["", "a..b", "a.b*", "#a", "a."] {
+            assert!(validate_pattern(pattern).is_err(), "{}", pattern);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use aes_gcm::aead::{KeyInit, OsRng};
+use aes_gcm::Aes256Gcm;
+
+// all the keys messages may be encrypted with. New messages are always
+// encrypted with the current key, retired keys are only kept around so the
+// messages written before a rotation can still be read
+pub struct KeyRing {
+    keys: HashMap<String, Aes256Gcm>,
+    current: String,
+}
+
+impl KeyRing {
+    // keys are written as `<key id>:<64 hex characters>`, one per line in a key
+    // file or comma separated in EDI_KEYS. The last key listed is the current
+    // one, so a key is rotated by appending a new entry
+    pub fn load(keys: Option<&str>, key_file: Option<&Path>) -> io::Result<Self> {
+        let key_file = match (keys, key_file) {
+            (Some(keys), None) => return KeyRing::parse(keys.split(',')),
+            (Some(_), Some(_)) => {
+                return Err(invalid(String::from(
+                    "Set either EDI_KEYS or EDI_KEY_FILE, not both",
+                )))
+            }
+            (None, None) => {
+                return Err(invalid(String::from("No encryption keys were configured")))
+            }
+            (None, Some(key_file)) => key_file,
+        };
+        match fs::read_to_string(key_file) {
+            Ok(contents) => KeyRing::parse(contents.lines()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => KeyRing::generate(key_file),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn get_current_id(&self) -> &str {
+        &self.current
+    }
+
+    pub fn get_current(&self) -> &Aes256Gcm {
+        &self.keys[&self.current]
+    }
+
+    pub fn get(&self, key_id: &str) -> Option<&Aes256Gcm> {
+        self.keys.get(key_id)
+    }
+
+    fn parse<'a>(entries: impl Iterator<Item = &'a str>) -> io::Result<Self> {
+        let mut keys = HashMap::new();
+        let mut current = None;
+        for entry in entries.map(str::trim) {
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            let (key_id, hex) = match entry.split_once(':') {
+                Some((id, hex)) if !id.trim().is_empty() => (id.trim(), hex.trim()),
+                _ => return Err(invalid(format!("The key entry {} is malformed", entry))),
+            };
+            let key = match decode_hex(hex) {
+                Some(bytes) if bytes.len() == 32 => bytes,
+                _ => {
+                    return Err(invalid(format!(
+                        "The key {} must be 64 hex characters",
+                        key_id
+                    )))
+                }
+            };
+            if keys.contains_key(key_id) {
+                return Err(invalid(format!("The key {} is listed twice", key_id)));
+            }
+            keys.insert(key_id.to_owned(), Aes256Gcm::new_from_slice(&key).unwrap());
+            current = Some(key_id.to_owned());
+        }
+        match current {
+            None => Err(invalid(String::from("No encryption keys were configured"))),
+            Some(current) => Ok(KeyRing { keys, current }),
+        }
+    }
+
+    // first start with a key file that does not exist yet, create it so the
+    // key survives restarts
+    fn generate(key_file: &Path) -> io::Result<Self> {
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+        let entry = format!("1:{}", encode_hex(&key));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(key_file)?;
+        writeln!(file, "{}", entry)?;
+        file.sync_all()?;
+        KeyRing::parse(std::iter::once(entry.as_str()))
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
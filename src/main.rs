@@ -1,32 +1,127 @@
-use actix_web::{error, web, App, HttpResponse, HttpServer};
-use aes_gcm::{
-    aead::{KeyInit, OsRng},
-    Aes256Gcm,
-};
+use actix_web::{error, rt, web, App, HttpResponse, HttpServer};
 use app_types::AppState;
-use exchange_api::{add_message_to_exchange, list_exchanges, new_exchange};
+use config::Config;
+use exchange_api::{
+    add_message_to_exchange, bind_queue, delete_exchange, describe_exchange, list_exchanges,
+    new_exchange, unbind_queue,
+};
 use futures::lock::Mutex;
 use general_api::ping;
-use message_api::{add_message_to_queue, delete_message, get_message};
-use queue_api::{list_queues, new_queue};
+use keys::KeyRing;
+use message_api::{
+    add_message_to_queue, change_message_visibility, delete_message, delete_message_batch,
+    get_message, release_message,
+};
+use persistence::wal::{FsyncPolicy, Wal};
+use queue_api::{
+    browse_queue, delete_queue, describe_queue, list_queues, new_queue, purge_queue,
+    remove_expired_messages, update_queue,
+};
+use redrive_api::{cancel_redrive, get_redrive, list_redrives, new_redrive};
 use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use tokio::sync::RwLock;
 
 mod app_types;
+mod config;
 mod exchange_api;
 mod general_api;
+mod keys;
 mod message_api;
+mod persistence;
 mod queue_api;
+mod redrive_api;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    // generate unique key on start up
-    let key = Aes256Gcm::generate_key(&mut OsRng);
-    let cipher = Aes256Gcm::new(&key);
+    let config = Config::from_env()?;
+    fs::create_dir_all(&config.data_dir)?;
+    let keys = KeyRing::load(config.keys.as_deref(), config.key_file.as_deref())?;
+
+    // rebuild whatever was persisted before the last shutdown
+    let restored = persistence::restore(&config.data_dir)?;
+    let mut counts = persistence::count_messages_by_key(&restored.queues)
+        .into_iter()
+        .collect::<Vec<(String, usize)>>();
+    counts.sort();
+    // a message that can't be decrypted would fail every receive of the
+    // batches it lands in, so every key still in use has to be listed
+    let unknown = counts
+        .iter()
+        .filter(|(key_id, _)| keys.get(key_id).is_none())
+        .map(|(key_id, count)| format!("{} ({} messages)", key_id, count))
+        .collect::<Vec<String>>();
+    if !unknown.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Stored messages were encrypted with keys that are not configured: {}",
+                unknown.join(", ")
+            ),
+        ));
+    }
+    for (key_id, count) in counts {
+        if key_id != keys.get_current_id() {
+            eprintln!(
+                "The retired key {} still protects {} messages",
+                key_id, count
+            );
+        }
+    }
+    let wal = Wal::open(&config.data_dir, restored.generation, config.fsync_policy)?;
 
     let queue_data = web::Data::new(AppState {
-        queues: Mutex::new(HashMap::new()),
-        exchanges: Mutex::new(HashMap::new()),
-        cipher: Mutex::new(cipher),
+        queues: RwLock::new(
+            restored
+                .queues
+                .into_iter()
+                .map(|(id, q)| (id, Arc::new(Mutex::new(q))))
+                .collect(),
+        ),
+        exchanges: Mutex::new(restored.exchanges),
+        keys,
+        wal,
+        data_dir: config.data_dir.clone(),
+        redrives: Mutex::new(HashMap::new()),
+    });
+
+    if let FsyncPolicy::Batched(interval) = config.fsync_policy {
+        let app_data = queue_data.clone();
+        rt::spawn(async move {
+            let mut ticker = rt::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = app_data.get_wal().sync() {
+                    eprintln!("Failed to sync the write-ahead log: {}", e);
+                }
+            }
+        });
+    }
+
+    let app_data = queue_data.clone();
+    let data_dir = config.data_dir.clone();
+    let snapshot_interval = config.snapshot_interval;
+    rt::spawn(async move {
+        let mut ticker = rt::time::interval(snapshot_interval);
+        ticker.tick().await; // the first tick completes immediately
+        loop {
+            ticker.tick().await;
+            if let Err(e) = persistence::take_snapshot(&app_data, &data_dir).await {
+                eprintln!("Failed to write a snapshot: {}", e);
+            }
+        }
+    });
+
+    let app_data = queue_data.clone();
+    let sweep_interval = config.sweep_interval;
+    rt::spawn(async move {
+        let mut ticker = rt::time::interval(sweep_interval);
+        loop {
+            ticker.tick().await;
+            remove_expired_messages(&app_data).await;
+        }
     });
 
     HttpServer::new(move || {
@@ -48,19 +143,38 @@ async fn main() -> std::io::Result<()> {
             .service(
                 web::scope("/queue")
                     .route("/list", web::get().to(list_queues))
-                    .route("/new", web::post().to(new_queue)),
+                    .route("/new", web::post().to(new_queue))
+                    .route("/delete", web::post().to(delete_queue))
+                    .route("/purge", web::post().to(purge_queue))
+                    .route("/update", web::post().to(update_queue))
+                    .route("/describe", web::get().to(describe_queue))
+                    .route("/browse", web::get().to(browse_queue)),
             )
             .service(
                 web::scope("/message")
                     .route("/new", web::post().to(add_message_to_queue))
                     .route("/get", web::get().to(get_message))
-                    .route("/delete", web::post().to(delete_message)),
+                    .route("/delete", web::post().to(delete_message))
+                    .route("/delete/batch", web::post().to(delete_message_batch))
+                    .route("/visibility", web::post().to(change_message_visibility))
+                    .route("/release", web::post().to(release_message)),
             )
             .service(
                 web::scope("/exchange")
                     .route("/list", web::get().to(list_exchanges))
                     .route("/new", web::post().to(new_exchange))
-                    .route("/add", web::post().to(add_message_to_exchange)),
+                    .route("/add", web::post().to(add_message_to_exchange))
+                    .route("/bind", web::post().to(bind_queue))
+                    .route("/unbind", web::post().to(unbind_queue))
+                    .route("/delete", web::post().to(delete_exchange))
+                    .route("/describe", web::get().to(describe_exchange)),
+            )
+            .service(
+                web::scope("/redrive")
+                    .route("/list", web::get().to(list_redrives))
+                    .route("/new", web::post().to(new_redrive))
+                    .route("/get", web::get().to(get_redrive))
+                    .route("/cancel", web::post().to(cancel_redrive)),
             )
     })
     .bind(("127.0.0.1", 8080))?
@@ -1,17 +1,26 @@
 use crate::app_types::{AppState, JsonResponse};
-use actix_web::{web, HttpResponse};
-use request::{DeleteMessageRequest, GetMessageRequest, GetMessageResponse, NewMessageRequest};
+use crate::queue_api::queue::{MessageOptions, QueueError, ReceiveOptions};
+use actix_web::{rt, web, HttpResponse};
+use chrono::Utc;
+use request::{
+    ChangeVisibilityRequest, DeleteMessageBatchRequest, DeleteMessageRequest, DeleteMessageResult,
+    GetMessageRequest, GetMessageResponse, NewMessageRequest, ReleaseMessageRequest,
+};
+use std::pin::pin;
+use std::time::{Duration, Instant};
 
 mod request;
 
+// the longest a receive may wait for messages to arrive
+const MAX_WAIT_SECONDS: u32 = 20;
+
 pub async fn add_message_to_queue(
     data: web::Data<AppState>,
     post_data: web::Json<NewMessageRequest>,
 ) -> HttpResponse {
     let queue_id = &post_data.queue_id;
 
-    let mut queues = data.get_queues().lock().await;
-    let queue = match queues.get_mut(queue_id) {
+    let queue = match data.get_queue(queue_id).await {
         None => {
             return HttpResponse::BadRequest().json(JsonResponse::new(
                 None::<String>,
@@ -20,23 +29,46 @@ pub async fn add_message_to_queue(
         }
         Some(q) => q,
     };
-
-    let cipher = data.get_cipher().lock().await;
-
     let messages_to_add = &post_data.messages;
+    if messages_to_add.iter().any(|m| m.ttl_seconds == Some(0)) {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            "The time to live 0 is invalid",
+        ));
+    }
+    let mut queue = queue.lock().await;
+    // checked before anything is added so the batch is not half sent
+    if queue.get_config().fifo && messages_to_add.iter().any(|m| m.group_id.is_none()) {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!(
+                "Queue {} is a FIFO queue, messages sent to it need a groupId",
+                queue_id
+            ),
+        ));
+    }
     let mut messages_to_send = vec![];
     for message in messages_to_add.iter() {
         let id = message.message_id.to_owned();
         let content = message.content.to_owned();
-        let message_added = match queue.add_to_queue(&cipher, id, content) {
-            Ok(s) => s,
-            Err(_) => {
-                return HttpResponse::InternalServerError().json(JsonResponse::new(
-                    None::<String>,
-                    "Something went wrong. Please try again.",
-                ))
-            }
+        let options = MessageOptions {
+            delay_seconds: message.delay_seconds,
+            ttl_seconds: message.ttl_seconds,
+            group_id: message.group_id.clone(),
+            deduplication_id: message.deduplication_id.clone(),
+            priority: message.priority,
+            attributes: message.attributes.clone(),
         };
+        let message_added =
+            match queue.add_to_queue(data.get_keys(), data.get_wal(), id, content, &options) {
+                Ok(s) => s,
+                Err(_) => {
+                    return HttpResponse::InternalServerError().json(JsonResponse::new(
+                        None::<String>,
+                        "Something went wrong. Please try again.",
+                    ))
+                }
+            };
         messages_to_send.push(message_added);
     }
 
@@ -48,8 +80,7 @@ pub async fn delete_message(
     post_data: web::Json<DeleteMessageRequest>,
 ) -> HttpResponse {
     let queue_id = &post_data.queue_id;
-    let mut queues = data.get_queues().lock().await;
-    let queue = match queues.get_mut(queue_id) {
+    let queue = match data.get_queue(queue_id).await {
         None => {
             return HttpResponse::BadRequest().json(JsonResponse::new(
                 None::<String>,
@@ -58,28 +89,26 @@ pub async fn delete_message(
         }
         Some(q) => q,
     };
+    let mut queue = queue.lock().await;
 
-    let message_uuid = &post_data.message_uuid;
-    match queue.rem_from_queue(message_uuid) {
-        None => HttpResponse::BadRequest().json(JsonResponse::new(
-            None::<String>,
-            format!("No message with uuid {} found, or the message is past the set read timeout - you cannot delete a message past its read timeout because another consumer may be using it.", message_uuid),
-        )),
-        Some(_) => HttpResponse::Accepted().json(JsonResponse::new(
-            format!("Successfully deleted uuid {}", message_uuid),
+    let receipt_handle = &post_data.receipt_handle;
+    match queue.rem_from_queue(data.get_wal(), receipt_handle) {
+        Err(e) => lease_error_response(e, receipt_handle),
+        Ok(m) => HttpResponse::Accepted().json(JsonResponse::new(
+            format!("Successfully deleted uuid {}", m.get_uuid()),
             None::<String>,
         )),
     }
 }
 
-pub async fn get_message(
+// deletes many messages under a single lock of the queue. Every receipt
+// handle is reported on, whether or not its message could be deleted
+pub async fn delete_message_batch(
     data: web::Data<AppState>,
-    query_data: web::Query<GetMessageRequest>,
+    post_data: web::Json<DeleteMessageBatchRequest>,
 ) -> HttpResponse {
-    let queue_id = &query_data.queue_id;
-
-    let mut queues = data.get_queues().lock().await;
-    let queue = match queues.get_mut(queue_id) {
+    let queue_id = &post_data.queue_id;
+    let queue = match data.get_queue(queue_id).await {
         None => {
             return HttpResponse::BadRequest().json(JsonResponse::new(
                 None::<String>,
@@ -88,19 +117,180 @@ pub async fn get_message(
         }
         Some(q) => q,
     };
+    let mut queue = queue.lock().await;
+
+    let results = post_data
+        .receipt_handles
+        .iter()
+        .map(
+            |receipt_handle| match queue.rem_from_queue(data.get_wal(), receipt_handle) {
+                Ok(_) => DeleteMessageResult::new(receipt_handle.to_owned(), None),
+                Err(e) => DeleteMessageResult::new(
+                    receipt_handle.to_owned(),
+                    Some(lease_error_message(&e, receipt_handle)),
+                ),
+            },
+        )
+        .collect::<Vec<DeleteMessageResult>>();
+    HttpResponse::Accepted().json(JsonResponse::new(results, None::<String>))
+}
+
+pub async fn change_message_visibility(
+    data: web::Data<AppState>,
+    post_data: web::Json<ChangeVisibilityRequest>,
+) -> HttpResponse {
+    set_visibility(
+        &data,
+        &post_data.queue_id,
+        &post_data.receipt_handle,
+        post_data.visibility_timeout,
+    )
+    .await
+}
+
+// hands a leased message back to the queue without waiting for its timeout
+pub async fn release_message(
+    data: web::Data<AppState>,
+    post_data: web::Json<ReleaseMessageRequest>,
+) -> HttpResponse {
+    set_visibility(&data, &post_data.queue_id, &post_data.receipt_handle, 0).await
+}
 
-    let cipher = data.get_cipher().lock().await;
-    let messages_to_send = match queue.dispatch(cipher) {
-        Ok(m) => m,
-        Err(_) => {
-            return HttpResponse::InternalServerError().json(JsonResponse::new(
+async fn set_visibility(
+    data: &AppState,
+    queue_id: &str,
+    receipt_handle: &str,
+    visibility_timeout: u32,
+) -> HttpResponse {
+    let queue = match data.get_queue(queue_id).await {
+        None => {
+            return HttpResponse::BadRequest().json(JsonResponse::new(
                 None::<String>,
-                "Something went wrong. Please try again.",
+                format!("No queue with id {} was found", queue_id),
             ))
         }
+        Some(q) => q,
+    };
+    let mut queue = queue.lock().await;
+
+    if let Err(e) = queue.change_visibility(data.get_wal(), receipt_handle, visibility_timeout) {
+        return lease_error_response(e, receipt_handle);
+    }
+    HttpResponse::Accepted().json(JsonResponse::new(
+        format!(
+            "Successfully made the message visible in {} seconds",
+            visibility_timeout
+        ),
+        None::<String>,
+    ))
+}
+
+// a stale receipt handle is reported apart from one for a message that is
+// gone, so consumers can tell that another consumer may be working on it
+fn lease_error_response(error: QueueError, receipt_handle: &str) -> HttpResponse {
+    let message = lease_error_message(&error, receipt_handle);
+    match error {
+        QueueError::MessageNotFound => {
+            HttpResponse::BadRequest().json(JsonResponse::new(None::<String>, message))
+        }
+        QueueError::StaleReceiptHandle => {
+            HttpResponse::Conflict().json(JsonResponse::new(None::<String>, message))
+        }
+        _ => HttpResponse::InternalServerError().json(JsonResponse::new(None::<String>, message)),
     }
-    .iter()
-    .map(|m| GetMessageResponse::new(m.get_id(), m.get_content(), m.get_uuid()))
-    .collect::<Vec<GetMessageResponse>>();
+}
+
+fn lease_error_message(error: &QueueError, receipt_handle: &str) -> String {
+    match error {
+        QueueError::MessageNotFound => {
+            format!("No message was found for the receipt handle {}", receipt_handle)
+        }
+        QueueError::StaleReceiptHandle => format!("The receipt handle {} is stale - the message was past its read timeout or received again, and another consumer may be using it.", receipt_handle),
+        _ => String::from("Something went wrong. Please try again."),
+    }
+}
+
+pub async fn get_message(
+    data: web::Data<AppState>,
+    query_data: web::Query<GetMessageRequest>,
+) -> HttpResponse {
+    let queue_id = &query_data.queue_id;
+    let wait_seconds = query_data.wait_seconds.unwrap_or(0);
+    if wait_seconds > MAX_WAIT_SECONDS {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!(
+                "The wait of {} seconds is invalid, it can be at most {} seconds",
+                wait_seconds, MAX_WAIT_SECONDS
+            ),
+        ));
+    }
+    if query_data.max_messages == Some(0) {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            "The max number of messages to receive 0 is invalid",
+        ));
+    }
+    if query_data.visibility_timeout == Some(0) {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            "The visibility timeout 0 is invalid",
+        ));
+    }
+    let options = ReceiveOptions {
+        max_messages: query_data.max_messages,
+        visibility_timeout: query_data.visibility_timeout,
+    };
+    let deadline = Instant::now() + Duration::from_secs(wait_seconds as u64);
+
+    // while nothing is visible the request waits until a message is added or
+    // the next leased or delayed message becomes visible, then tries again
+    let messages = loop {
+        let (mut queue, mut dead_letter_queue) =
+            match data.lock_queue_with_dead_letters(queue_id).await {
+                None => {
+                    return HttpResponse::BadRequest().json(JsonResponse::new(
+                        None::<String>,
+                        format!("No queue with id {} was found", queue_id),
+                    ))
+                }
+                Some(q) => q,
+            };
+
+        let messages = match queue.dispatch(
+            data.get_keys(),
+            data.get_wal(),
+            &options,
+            dead_letter_queue.as_deref_mut(),
+        ) {
+            Ok(m) => m,
+            Err(_) => {
+                return HttpResponse::InternalServerError().json(JsonResponse::new(
+                    None::<String>,
+                    "Something went wrong. Please try again.",
+                ))
+            }
+        };
+        let now = Instant::now();
+        if !messages.is_empty() || now >= deadline {
+            break messages;
+        }
+
+        let notify = queue.get_notify();
+        let mut notified = pin!(notify.notified());
+        notified.as_mut().enable();
+        let mut wake_at = deadline;
+        if let Some(visible_at) = queue.next_visible_at() {
+            let visible_in = (visible_at - Utc::now()).to_std().unwrap_or_default();
+            wake_at = wake_at.min(now + visible_in);
+        }
+        drop((queue, dead_letter_queue));
+        let _ = rt::time::timeout(wake_at - now, notified).await;
+    };
+
+    let messages_to_send = messages
+        .iter()
+        .map(GetMessageResponse::new)
+        .collect::<Vec<GetMessageResponse>>();
     HttpResponse::Accepted().json(JsonResponse::new(messages_to_send, None::<String>))
 }
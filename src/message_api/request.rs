@@ -1,10 +1,21 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
+use crate::queue_api::queue::{DeadLetterInfo, DecryptedMessage};
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct NewMessage {
     pub message_id: String,
     pub content: String,
+    pub delay_seconds: Option<u32>,
+    pub ttl_seconds: Option<u32>,
+    pub group_id: Option<String>,
+    pub deduplication_id: Option<String>,
+    pub priority: Option<u8>,
+    #[serde(default)]
+    pub attributes: BTreeMap<String, String>,
 }
 
 #[derive(Deserialize)]
@@ -18,6 +29,9 @@ pub struct NewMessageRequest {
 #[serde(rename_all = "camelCase")]
 pub struct GetMessageRequest {
     pub queue_id: String,
+    pub wait_seconds: Option<u32>,
+    pub max_messages: Option<u32>,
+    pub visibility_timeout: Option<u32>,
 }
 
 #[derive(Serialize)]
@@ -26,21 +40,75 @@ pub struct GetMessageResponse {
     pub message_id: String,
     pub content: String,
     pub uuid: String,
+    pub receipt_handle: String,
+    pub receive_count: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dead_letter: Option<DeadLetterInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<String, String>,
 }
 
 impl GetMessageResponse {
-    pub fn new(message_id: String, content: String, uuid: String) -> Self {
+    pub fn new(message: &DecryptedMessage) -> Self {
         GetMessageResponse {
-            message_id,
-            content,
-            uuid,
+            message_id: message.get_id(),
+            content: message.get_content(),
+            uuid: message.get_uuid(),
+            receipt_handle: message.get_receipt_handle(),
+            receive_count: message.get_receive_count(),
+            dead_letter: message.get_dead_letter(),
+            group_id: message.get_group_id(),
+            attributes: message.get_attributes(),
         }
     }
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteMessageBatchRequest {
+    pub queue_id: String,
+    pub receipt_handles: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteMessageResult {
+    pub receipt_handle: String,
+    pub deleted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl DeleteMessageResult {
+    pub fn new(receipt_handle: String, error: Option<String>) -> Self {
+        DeleteMessageResult {
+            receipt_handle,
+            deleted: error.is_none(),
+            error,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeVisibilityRequest {
+    pub queue_id: String,
+    pub receipt_handle: String,
+    pub visibility_timeout: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReleaseMessageRequest {
+    pub queue_id: String,
+    pub receipt_handle: String,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DeleteMessageRequest {
     pub queue_id: String,
-    pub message_uuid: String,
+    pub receipt_handle: String,
 }
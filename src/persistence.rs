@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::app_types::AppState;
+use crate::exchange_api::binding::Binding;
+use crate::exchange_api::exchange::Exchange;
+use crate::queue_api::queue::{Lease, Message, Queue, QueueConfig};
+use snapshot::Snapshot;
+
+pub(crate) mod snapshot;
+pub(crate) mod wal;
+
+// every state change that has to survive a restart is written as one of these
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum Record {
+    NewQueue {
+        uuid: Uuid,
+        config: QueueConfig,
+    },
+    NewExchange {
+        exchange: Exchange,
+    },
+    #[serde(rename_all = "camelCase")]
+    AddMessage {
+        queue_id: String,
+        message: Message,
+    },
+    // a message was received or had its visibility changed
+    #[serde(rename_all = "camelCase")]
+    LeaseMessage {
+        queue_id: String,
+        uuid: Uuid,
+        lease: Lease,
+    },
+    #[serde(rename_all = "camelCase")]
+    RemoveMessage {
+        queue_id: String,
+        uuid: Uuid,
+    },
+    // the queue is also unbound from every exchange
+    #[serde(rename_all = "camelCase")]
+    DeleteQueue {
+        queue_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    PurgeQueue {
+        queue_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    BindQueue {
+        exchange_id: String,
+        binding: Binding,
+    },
+    #[serde(rename_all = "camelCase")]
+    UnbindQueue {
+        exchange_id: String,
+        binding: Binding,
+    },
+    #[serde(rename_all = "camelCase")]
+    DeleteExchange {
+        exchange_id: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    UpdateQueue {
+        queue_id: String,
+        config: QueueConfig,
+    },
+}
+
+pub struct RestoredState {
+    pub queues: HashMap<String, Queue>,
+    pub exchanges: HashMap<String, Exchange>,
+    pub generation: u64, // the log segment new records should be appended to
+}
+
+// rebuilds the queues and exchanges from the newest readable snapshot and
+// the log segments written after it
+pub fn restore(dir: &Path) -> io::Result<RestoredState> {
+    let mut state = RestoredState {
+        queues: HashMap::new(),
+        exchanges: HashMap::new(),
+        generation: 0,
+    };
+
+    let snapshots = list_generations(dir, "snapshot-", ".json")?;
+    if !snapshots.is_empty() {
+        let snapshot = match snapshots
+            .iter()
+            .rev()
+            .find_map(|g| match snapshot::read(dir, *g) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    eprintln!("Skipping snapshot {}: {}", g, e);
+                    None
+                }
+            }) {
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("None of the snapshots in {} could be read", dir.display()),
+                ))
+            }
+            Some(s) => s,
+        };
+        state.generation = snapshot.generation;
+        for queue in snapshot.queues {
+            let mut restored = Queue::restore(queue.config, queue.uuid, dir)?;
+            for message in queue.messages {
+                restored.restore_message(message)?;
+            }
+            state
+                .queues
+                .insert(restored.get_config().id.to_owned(), restored);
+        }
+        for mut exchange in snapshot.exchanges {
+            exchange.rebuild_router();
+            state.exchanges.insert(exchange.id.to_owned(), exchange);
+        }
+    }
+
+    let segments = list_generations(dir, "wal-", ".log")?
+        .into_iter()
+        .filter(|g| *g >= state.generation)
+        .collect::<Vec<u64>>();
+    for (idx, generation) in segments.iter().enumerate() {
+        let is_tail = idx == segments.len() - 1;
+        for record in wal::read_records(&wal::segment_path(dir, *generation), is_tail)? {
+            apply(&mut state, record, dir)?;
+        }
+        state.generation = *generation;
+    }
+    Ok(state)
+}
+
+// writes a snapshot of the current state and drops the log segments it covers.
+// Every queue is locked at once while the state is copied, so the snapshot is
+// consistent with the log segment it starts. Producers and consumers pause for
+// as long as copying the messages of the MEMORY queues takes, while writing
+// the snapshot to disk happens after the locks are released
+pub async fn take_snapshot(data: &AppState, dir: &Path) -> io::Result<()> {
+    let snapshot = {
+        let queue_map = data.get_queues().read().await;
+        let mut queue_ids = queue_map.keys().collect::<Vec<&String>>();
+        queue_ids.sort();
+        let mut queues = vec![];
+        for queue_id in queue_ids {
+            queues.push(queue_map[queue_id].lock().await);
+        }
+        let exchanges = data.get_exchanges().lock().await;
+        let generation = data.get_wal().rotate()?;
+        Snapshot::capture(generation, queues.iter().map(|q| &**q), &exchanges)
+    };
+    snapshot::write(dir, &snapshot)?;
+
+    // the previous snapshot and its log segments are kept as a fallback
+    // in case the newest snapshot cannot be read back
+    let snapshots = list_generations(dir, "snapshot-", ".json")?;
+    if snapshots.len() < 2 {
+        return Ok(());
+    }
+    let oldest_kept = snapshots[snapshots.len() - 2];
+    for generation in snapshots.iter().filter(|g| **g < oldest_kept) {
+        fs::remove_file(snapshot::snapshot_path(dir, *generation))?;
+    }
+    for generation in list_generations(dir, "wal-", ".log")?
+        .iter()
+        .filter(|g| **g < oldest_kept)
+    {
+        fs::remove_file(wal::segment_path(dir, *generation))?;
+    }
+    Ok(())
+}
+
+fn apply(state: &mut RestoredState, record: Record, dir: &Path) -> io::Result<()> {
+    match record {
+        Record::NewQueue { uuid, config } => {
+            state
+                .queues
+                .insert(config.id.to_owned(), Queue::restore(config, uuid, dir)?);
+        }
+        Record::NewExchange { mut exchange } => {
+            exchange.rebuild_router();
+            state.exchanges.insert(exchange.id.to_owned(), exchange);
+        }
+        Record::AddMessage { queue_id, message } => {
+            if let Some(queue) = state.queues.get_mut(&queue_id) {
+                queue.restore_message(message)?;
+            }
+        }
+        Record::LeaseMessage {
+            queue_id,
+            uuid,
+            lease,
+        } => {
+            if let Some(queue) = state.queues.get_mut(&queue_id) {
+                queue.restore_lease(&uuid, lease)?;
+            }
+        }
+        Record::RemoveMessage { queue_id, uuid } => {
+            if let Some(queue) = state.queues.get_mut(&queue_id) {
+                queue.forget_message(&uuid)?;
+            }
+        }
+        Record::DeleteQueue { queue_id } => {
+            if let Some(mut queue) = state.queues.remove(&queue_id) {
+                queue.destroy()?;
+            }
+            for exchange in state.exchanges.values_mut() {
+                exchange.unbind_queue(&queue_id);
+            }
+        }
+        Record::PurgeQueue { queue_id } => {
+            if let Some(queue) = state.queues.get_mut(&queue_id) {
+                queue.forget_messages()?;
+            }
+        }
+        Record::BindQueue {
+            exchange_id,
+            binding,
+        } => {
+            if let Some(exchange) = state.exchanges.get_mut(&exchange_id) {
+                exchange.bind(binding);
+            }
+        }
+        Record::UnbindQueue {
+            exchange_id,
+            binding,
+        } => {
+            if let Some(exchange) = state.exchanges.get_mut(&exchange_id) {
+                exchange.unbind(&binding);
+            }
+        }
+        Record::DeleteExchange { exchange_id } => {
+            state.exchanges.remove(&exchange_id);
+        }
+        Record::UpdateQueue { queue_id, config } => {
+            if let Some(queue) = state.queues.get_mut(&queue_id) {
+                queue.set_config(config);
+            }
+        }
+    }
+    Ok(())
+}
+
+// how many stored messages were encrypted with each key, so operators know
+// when a retired key is no longer needed
+pub fn count_messages_by_key(queues: &HashMap<String, Queue>) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+    for message in queues.values().flat_map(|q| q.get_messages()) {
+        *counts.entry(message.get_key_id().to_owned()).or_insert(0) += 1;
+    }
+    counts
+}
+
+// finds the generation numbers of the files named `{prefix}{generation}{suffix}`
+fn list_generations(dir: &Path, prefix: &str, suffix: &str) -> io::Result<Vec<u64>> {
+    let mut generations = vec![];
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let generation = name
+            .to_str()
+            .and_then(|n| n.strip_prefix(prefix))
+            .and_then(|n| n.strip_suffix(suffix))
+            .and_then(|n| n.parse::<u64>().ok());
+        if let Some(g) = generation {
+            generations.push(g);
+        }
+    }
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use super::*;
+    use snapshot::QueueSnapshot;
+    use wal::{FsyncPolicy, Wal};
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("edi-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(queue_id: &str) -> QueueConfig {
+        serde_json::from_value(serde_json::json!({
+            "id": queue_id,
+            "read_timeout": 30,
+            "max_batch": 10,
+            "storage": "MEMORY",
+        }))
+        .unwrap()
+    }
+
+    fn new_queue(queue_id: &str) -> Record {
+        Record::NewQueue {
+            uuid: Uuid::new_v4(),
+            config: config(queue_id),
+        }
+    }
+
+    fn write_segment(dir: &Path, generation: u64, records: &[Record]) {
+        let wal = Wal::open(dir, generation, FsyncPolicy::Os).unwrap();
+        wal.append_all(records).unwrap();
+    }
+
+    fn append_bytes(path: &Path, bytes: &[u8]) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    fn queue_ids(state: &RestoredState) -> Vec<&str> {
+        let mut queue_ids = state
+            .queues
+            .keys()
+            .map(|id| id.as_str())
+            .collect::<Vec<_>>();
+        queue_ids.sort();
+        queue_ids
+    }
+
+    #[test]
+    fn a_torn_final_record_is_cut_off_and_skipped() {
+        let dir = temp_dir();
+        write_segment(&dir, 0, &[new_queue("a"), new_queue("b")]);
+        let path = wal::segment_path(&dir, 0);
+        let valid_len = fs::metadata(&path).unwrap().len();
+        append_bytes(&path, br#"{"op":"newQueue","uuid":"#);
+
+        let records = wal::read_records::<Record>(&path, true).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(fs::metadata(&path).unwrap().len(), valid_len);
+        assert_eq!(queue_ids(&restore(&dir).unwrap()), ["a", "b"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_corrupt_record_before_the_last_segment_fails_the_restore() {
+        let dir = temp_dir();
+        write_segment(&dir, 0, &[new_queue("a")]);
+        append_bytes(&wal::segment_path(&dir, 0), br#"{"op":"newQueue","uuid":"#);
+        write_segment(&dir, 1, &[new_queue("b")]);
+
+        let error = restore(&dir).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn an_unreadable_snapshot_falls_back_to_the_previous_one() {
+        let dir = temp_dir();
+        let older = Snapshot {
+            generation: 1,
+            queues: vec![QueueSnapshot {
+                uuid: Uuid::new_v4(),
+                config: config("a"),
+                messages: vec![],
+            }],
+            exchanges: vec![],
+        };
+        snapshot::write(&dir, &older).unwrap();
+        write_segment(&dir, 1, &[new_queue("b")]);
+        fs::write(snapshot::snapshot_path(&dir, 2), b"{\"generation\":2,").unwrap();
+        write_segment(&dir, 2, &[new_queue("c")]);
+
+        let state = restore(&dir).unwrap();
+        assert_eq!(queue_ids(&state), ["a", "b", "c"]);
+        assert_eq!(state.generation, 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
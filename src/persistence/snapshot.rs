@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::exchange_api::exchange::Exchange;
+use crate::queue_api::queue::{Message, Queue, QueueConfig};
+
+// a point in time copy of every queue and exchange. `generation` is the first
+// log segment that is not already covered by the snapshot
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub generation: u64,
+    pub queues: Vec<QueueSnapshot>,
+    pub exchanges: Vec<Exchange>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QueueSnapshot {
+    pub uuid: Uuid,
+    pub config: QueueConfig,
+    pub messages: Vec<Message>,
+}
+
+impl Snapshot {
+    pub fn capture<'a>(
+        generation: u64,
+        queues: impl Iterator<Item = &'a Queue>,
+        exchanges: &HashMap<String, Exchange>,
+    ) -> Self {
+        Snapshot {
+            generation,
+            queues: queues
+                .map(|q| QueueSnapshot {
+                    uuid: q.get_raw_uuid(),
+                    config: q.get_config().clone(),
+                    // durable queues restore their messages on their own
+                    messages: match q.is_durable() {
+                        true => vec![],
+                        false => q.get_messages().cloned().collect(),
+                    },
+                })
+                .collect(),
+            exchanges: exchanges.values().cloned().collect(),
+        }
+    }
+}
+
+pub fn snapshot_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("snapshot-{}.json", generation))
+}
+
+// the snapshot is written to a temporary file first so a crash midway
+// never leaves a half written snapshot behind
+pub fn write(dir: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    let tmp_path = dir.join("snapshot.tmp");
+    let file = File::create(&tmp_path)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, snapshot)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, snapshot_path(dir, snapshot.generation))?;
+    File::open(dir)?.sync_all()
+}
+
+pub fn read(dir: &Path, generation: u64) -> io::Result<Snapshot> {
+    let file = File::open(snapshot_path(dir, generation))?;
+    let snapshot: Snapshot = serde_json::from_reader(BufReader::new(file))?;
+    if snapshot.generation != generation {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Snapshot {} claims to be generation {}",
+                generation, snapshot.generation
+            ),
+        ));
+    }
+    Ok(snapshot)
+}
@@ -0,0 +1,215 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+
+use super::Record;
+
+#[derive(Clone, Copy, Debug)]
+pub enum FsyncPolicy {
+    Always,            // fsync after every record
+    Batched(Duration), // fsync on a fixed interval
+    Os,                // leave flushing to the operating system
+}
+
+// the log is split into numbered segments so that everything written
+// before a snapshot can be dropped once the snapshot is on disk
+struct Segment {
+    file: Arc<File>,
+    generation: u64,
+    written: u64, // records appended so far, counted across segments
+}
+
+// appends only hold the segment lock while writing their record. Fsyncs
+// happen under `synced` instead, and one fsync covers every record written
+// before it started, so appends from different queues waiting to be synced
+// share a single fsync instead of queueing up for one each
+pub struct Wal {
+    segment: Mutex<Segment>,
+    synced: Mutex<u64>, // records known to be on disk, always locked before `segment`
+    dir: PathBuf,
+    policy: FsyncPolicy,
+}
+
+impl Wal {
+    pub fn open(dir: &Path, generation: u64, policy: FsyncPolicy) -> io::Result<Self> {
+        let file = open_segment(dir, generation)?;
+        Ok(Wal {
+            segment: Mutex::new(Segment {
+                file: Arc::new(file),
+                generation,
+                written: 0,
+            }),
+            synced: Mutex::new(0),
+            dir: dir.to_path_buf(),
+            policy,
+        })
+    }
+
+    pub fn append(&self, record: &Record) -> io::Result<()> {
+        self.append_all(std::slice::from_ref(record))
+    }
+
+    // writes the records together, so they share a single fsync
+    pub fn append_all(&self, records: &[Record]) -> io::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut lines = vec![];
+        for record in records {
+            serde_json::to_writer(&mut lines, record)?;
+            lines.push(b'\n');
+        }
+        let written = {
+            let mut segment = self.segment.lock().unwrap();
+            (&*segment.file).write_all(&lines)?;
+            segment.written += records.len() as u64;
+            segment.written
+        };
+        if let FsyncPolicy::Always = self.policy {
+            self.sync_through(written)?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        let written = self.segment.lock().unwrap().written;
+        self.sync_through(written)
+    }
+
+    // makes sure the first `record` records are on disk. Whoever gets the lock
+    // first fsyncs everything written so far, so the appends that waited for
+    // it usually find their record already synced
+    fn sync_through(&self, record: u64) -> io::Result<()> {
+        let mut synced = self.synced.lock().unwrap();
+        if *synced >= record {
+            return Ok(());
+        }
+        let (file, written) = {
+            let segment = self.segment.lock().unwrap();
+            (segment.file.clone(), segment.written)
+        };
+        file.sync_data()?;
+        *synced = written;
+        Ok(())
+    }
+
+    // seals the current segment and starts a new one, returning its generation
+    pub fn rotate(&self) -> io::Result<u64> {
+        let mut synced = self.synced.lock().unwrap();
+        let mut segment = self.segment.lock().unwrap();
+        segment.file.sync_data()?;
+        let generation = segment.generation + 1;
+        segment.file = Arc::new(open_segment(&self.dir, generation)?);
+        segment.generation = generation;
+        *synced = segment.written;
+        Ok(generation)
+    }
+}
+
+pub fn segment_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("wal-{}.log", generation))
+}
+
+fn open_segment(dir: &Path, generation: u64) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, generation))
+}
+
+// reads back every record in a log file. A record that was only partly written
+// when the process died can only be the last one, so when `tail` is set a
+// final record that does not parse is cut off instead of failing the replay
+pub fn read_records<T: DeserializeOwned>(path: &Path, tail: bool) -> io::Result<Vec<T>> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut reader = BufReader::new(file);
+    let mut records = vec![];
+    let mut valid_len = 0;
+    let mut line = vec![];
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        let complete = line.last() == Some(&b'\n');
+        match serde_json::from_slice(&line) {
+            Ok(record) if complete => {
+                records.push(record);
+                valid_len += line.len() as u64;
+            }
+            Ok(_) | Err(_) => {
+                let is_last = reader.fill_buf()?.is_empty();
+                if !(tail && is_last) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Record {} of {} is corrupt",
+                            records.len() + 1,
+                            path.display()
+                        ),
+                    ));
+                }
+                eprintln!("Dropping a torn record at the end of {}", path.display());
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(valid_len)?;
+                break;
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn concurrent_appends_are_all_synced_in_order() {
+        let dir = std::env::temp_dir().join(format!("edi-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let wal = Wal::open(&dir, 0, FsyncPolicy::Always).unwrap();
+        thread::scope(|s| {
+            for t in 0..8 {
+                let wal = &wal;
+                s.spawn(move || {
+                    for i in 0..50 {
+                        let record = Record::PurgeQueue {
+                            queue_id: format!("{}-{}", t, i),
+                        };
+                        wal.append(&record).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(*wal.synced.lock().unwrap(), 400);
+
+        let mut next = [0; 8];
+        for record in read_records::<Record>(&segment_path(&dir, 0), false).unwrap() {
+            let queue_id = match record {
+                Record::PurgeQueue { queue_id } => queue_id,
+                _ => panic!("unexpected record"),
+            };
+            let (t, i) = queue_id.split_once('-').unwrap();
+            let (t, i) = (t.parse::<usize>().unwrap(), i.parse::<usize>().unwrap());
+            assert_eq!(i, next[t]);
+            next[t] += 1;
+        }
+        assert_eq!(next, [50; 8]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,52 +1,520 @@
-use crate::app_types::{AppState, JsonResponse};
+use crate::app_types::{AppState, JsonResponse, QueueMap};
+use crate::persistence::Record;
 use actix_web::{web, HttpResponse};
-use queue::Queue;
-use request::NewQueueRequest;
+use chrono::Utc;
+use futures::lock::Mutex;
+use queue::{
+    DeadLetterPolicy, PriorityPolicy, Queue, QueueConfig, DEFAULT_DEDUPLICATION_WINDOW_SECONDS,
+};
+use request::{
+    BrowseQueueRequest, BrowseQueueResponse, DeleteQueueRequest, NewQueueRequest, QueueDescription,
+    QueueRequest, UpdateQueueRequest,
+};
 use std::collections::hash_map::Entry;
+use std::sync::Arc;
 
 pub(crate) mod queue;
 mod request;
 
+// the most expired messages removed from a queue while holding its lock
+const SWEEP_BATCH_SIZE: usize = 1000;
+// how many messages a browse returns unless it asks for fewer or more
+const DEFAULT_BROWSE_LIMIT: usize = 10;
+const MAX_BROWSE_LIMIT: usize = 100;
+
 pub async fn new_queue(
     data: web::Data<AppState>,
     post_data: web::Json<NewQueueRequest>,
 ) -> HttpResponse {
-    if post_data.max_batch <= 0 {
-        return HttpResponse::BadRequest().json(JsonResponse::new(
+    let dead_letter = match dead_letter_policy(
+        post_data.max_receive_count,
+        post_data.dead_letter_queue_id.as_ref(),
+    ) {
+        Err(e) => return HttpResponse::BadRequest().json(JsonResponse::new(None::<String>, e)),
+        Ok(p) => p,
+    };
+    let priority = match (post_data.max_priority, post_data.priority_aging_seconds) {
+        (None, None) => None,
+        (Some(max_priority), aging_seconds) => Some(PriorityPolicy {
+            max_priority,
+            aging_seconds,
+        }),
+        (None, Some(_)) => {
+            return HttpResponse::BadRequest().json(JsonResponse::new(
+                None::<String>,
+                "priorityAgingSeconds needs a maxPriority",
+            ))
+        }
+    };
+    let config = QueueConfig {
+        id: post_data.queue_id.to_owned(),
+        read_timeout: post_data.read_timeout,
+        max_batch: post_data.max_batch,
+        storage: post_data.storage,
+        dead_letter,
+        delay_seconds: post_data.delay_seconds,
+        retention_seconds: post_data.retention_seconds,
+        dead_letter_expired: post_data.dead_letter_expired,
+        fifo: post_data.fifo,
+        deduplication_window_seconds: post_data
+            .deduplication_window_seconds
+            .unwrap_or(DEFAULT_DEDUPLICATION_WINDOW_SECONDS),
+        content_based_deduplication: post_data.content_based_deduplication,
+        priority,
+    };
+    let mut queues = data.get_queues().write().await;
+    if let Err(e) = validate_config(&config, &queues).await {
+        return HttpResponse::BadRequest().json(JsonResponse::new(None::<String>, e));
+    }
+    match queues.entry(post_data.queue_id.to_owned()) {
+        Entry::Vacant(_) => {
+            let queue = match Queue::new(config, data.get_data_dir()) {
+                Ok(q) => q,
+                Err(_) => {
+                    return HttpResponse::InternalServerError().json(JsonResponse::new(
+                        None::<String>,
+                        "Something went wrong. Please try again.",
+                    ))
+                }
+            };
+            let queue_uuid = &queue.get_uuid();
+            let record = Record::NewQueue {
+                uuid: queue.get_raw_uuid(),
+                config: queue.get_config().clone(),
+            };
+            // a durable queue's messages are acknowledged without the log,
+            // so the queue itself must not depend on the fsync policy
+            let wal = data.get_wal();
+            if wal.append(&record).is_err() || (queue.is_durable() && wal.sync().is_err()) {
+                return HttpResponse::InternalServerError().json(JsonResponse::new(
+                    None::<String>,
+                    "Something went wrong. Please try again.",
+                ));
+            }
+            queues.insert(post_data.queue_id.to_owned(), Arc::new(Mutex::new(queue)));
+            HttpResponse::Accepted().json(JsonResponse::new(queue_uuid, None::<String>))
+        }
+        Entry::Occupied(_) => HttpResponse::Conflict().json(JsonResponse::new(
+            None::<String>,
+            format!("A queue with id {} already exists", post_data.queue_id),
+        )),
+    }
+}
+
+// changes the settings of a queue in place. Settings that are left out stay
+// as they are, and the messages in the queue are kept
+pub async fn update_queue(
+    data: web::Data<AppState>,
+    post_data: web::Json<UpdateQueueRequest>,
+) -> HttpResponse {
+    let queue_id = &post_data.queue_id;
+    let queues = data.get_queues().write().await;
+    let queue = match queues.get(queue_id) {
+        None => {
+            return HttpResponse::BadRequest().json(JsonResponse::new(
+                None::<String>,
+                format!("No queue with id {} was found", queue_id),
+            ))
+        }
+        Some(q) => q.clone(),
+    };
+
+    let mut config = queue.lock().await.get_config().clone();
+    if let Some(read_timeout) = post_data.read_timeout {
+        config.read_timeout = read_timeout;
+    }
+    if let Some(max_batch) = post_data.max_batch {
+        config.max_batch = max_batch;
+    }
+    if let Some(delay_seconds) = post_data.delay_seconds {
+        config.delay_seconds = delay_seconds;
+    }
+    if let Some(retention_seconds) = post_data.retention_seconds {
+        config.retention_seconds = retention_seconds;
+    }
+    if let Some(dead_letter_expired) = post_data.dead_letter_expired {
+        config.dead_letter_expired = dead_letter_expired;
+    }
+    if let Some(window) = post_data.deduplication_window_seconds {
+        config.deduplication_window_seconds = window;
+    }
+    if let Some(content_based_deduplication) = post_data.content_based_deduplication {
+        config.content_based_deduplication = content_based_deduplication;
+    }
+    // a new max priority keeps the aging period, and null turns both off
+    if let Some(max_priority) = post_data.max_priority {
+        let aging_seconds = config.priority.as_ref().and_then(|p| p.aging_seconds);
+        config.priority = max_priority.map(|max_priority| PriorityPolicy {
+            max_priority,
+            aging_seconds,
+        });
+    }
+    if let Some(aging_seconds) = post_data.priority_aging_seconds {
+        match config.priority.as_mut() {
+            Some(policy) => policy.aging_seconds = aging_seconds,
+            None if aging_seconds.is_some() => {
+                return HttpResponse::BadRequest().json(JsonResponse::new(
+                    None::<String>,
+                    "priorityAgingSeconds needs a maxPriority",
+                ))
+            }
+            None => (),
+        }
+    }
+    match (
+        &post_data.max_receive_count,
+        &post_data.dead_letter_queue_id,
+    ) {
+        (None, None) => (),
+        (Some(max_receive_count), Some(dead_letter_queue_id)) => {
+            config.dead_letter =
+                match dead_letter_policy(*max_receive_count, dead_letter_queue_id.as_ref()) {
+                    Err(e) => {
+                        return HttpResponse::BadRequest()
+                            .json(JsonResponse::new(None::<String>, e))
+                    }
+                    Ok(p) => p,
+                }
+        }
+        _ => {
+            return HttpResponse::BadRequest().json(JsonResponse::new(
+                None::<String>,
+                "maxReceiveCount and deadLetterQueueId must be given together",
+            ))
+        }
+    }
+    // the queue map is write locked, so the queues the checks look at
+    // cannot change until the update is done
+    if let Err(e) = validate_config(&config, &queues).await {
+        return HttpResponse::BadRequest().json(JsonResponse::new(None::<String>, e));
+    }
+
+    let mut queue = queue.lock().await;
+    if queue.update_config(data.get_wal(), config).is_err() {
+        return HttpResponse::InternalServerError().json(JsonResponse::new(
+            None::<String>,
+            "Something went wrong. Please try again.",
+        ));
+    }
+    HttpResponse::Accepted().json(JsonResponse::new(
+        format!("Successfully updated queue {}", queue_id),
+        None::<String>,
+    ))
+}
+
+fn dead_letter_policy(
+    max_receive_count: Option<u32>,
+    dead_letter_queue_id: Option<&String>,
+) -> Result<Option<DeadLetterPolicy>, String> {
+    match (max_receive_count, dead_letter_queue_id) {
+        (None, None) => Ok(None),
+        (Some(max_receive_count), Some(dead_letter_queue_id)) => Ok(Some(DeadLetterPolicy {
+            max_receive_count,
+            dead_letter_queue_id: dead_letter_queue_id.to_owned(),
+        })),
+        _ => Err(String::from(
+            "maxReceiveCount and deadLetterQueueId must be given together",
+        )),
+    }
+}
+
+// the rules a queue's settings have to follow, whether the queue is new or
+// being updated. `queues` has to be the locked queue map, and the queue
+// being checked must not be locked by the caller
+async fn validate_config(config: &QueueConfig, queues: &QueueMap) -> Result<(), String> {
+    if config.max_batch == 0 {
+        return Err(format!(
+            "The max number of messages to send and receive at once {} is invalid",
+            config.max_batch
+        ));
+    }
+    if config.read_timeout == 0 {
+        return Err(format!(
+            "The read timeout {} is invalid",
+            config.read_timeout
+        ));
+    }
+    if config.deduplication_window_seconds == 0 {
+        return Err(String::from("The deduplication window 0 is invalid"));
+    }
+    if let Some(policy) = &config.priority {
+        if policy.max_priority == 0 {
+            return Err(String::from("The max priority 0 is invalid"));
+        }
+        if policy.aging_seconds == Some(0) {
+            return Err(String::from("The priority aging period 0 is invalid"));
+        }
+    }
+    if config.retention_seconds == Some(0) {
+        return Err(String::from("The retention period 0 is invalid"));
+    }
+    let policy = match &config.dead_letter {
+        None if config.dead_letter_expired => {
+            return Err(String::from("deadLetterExpired needs a deadLetterQueueId"))
+        }
+        None => return Ok(()),
+        Some(p) => p,
+    };
+    if policy.max_receive_count == 0 {
+        return Err(format!(
+            "The max receive count {} is invalid",
+            policy.max_receive_count
+        ));
+    }
+    if policy.dead_letter_queue_id == config.id {
+        return Err(String::from("A queue cannot be its own dead-letter queue"));
+    }
+    if !queues.contains_key(&policy.dead_letter_queue_id) {
+        return Err(format!(
+            "No queue with id {} was found",
+            policy.dead_letter_queue_id
+        ));
+    }
+    // follow the dead-letter queues of the dead-letter queue, a queue must
+    // not end up dead-lettering into itself
+    let mut next = Some(policy.dead_letter_queue_id.to_owned());
+    while let Some(queue_id) = next {
+        if queue_id == config.id {
+            return Err(format!(
+                "Dead-lettering into {} would send messages back to {}",
+                policy.dead_letter_queue_id, config.id
+            ));
+        }
+        next = match queues.get(&queue_id) {
+            None => None,
+            Some(q) => q
+                .lock()
+                .await
+                .get_config()
+                .dead_letter
+                .as_ref()
+                .map(|p| p.dead_letter_queue_id.to_owned()),
+        };
+    }
+    Ok(())
+}
+
+// deleting a queue that other queues dead-letter into is refused. Queues
+// bound to exchanges are only deleted when asked to cascade, which unbinds
+// them from those exchanges
+pub async fn delete_queue(
+    data: web::Data<AppState>,
+    post_data: web::Json<DeleteQueueRequest>,
+) -> HttpResponse {
+    let queue_id = &post_data.queue_id;
+    let mut queues = data.get_queues().write().await;
+    let queue = match queues.get(queue_id) {
+        None => {
+            return HttpResponse::BadRequest().json(JsonResponse::new(
+                None::<String>,
+                format!("No queue with id {} was found", queue_id),
+            ))
+        }
+        Some(q) => q.clone(),
+    };
+    for (other_id, other) in queues.iter() {
+        let dead_letter = other.lock().await.get_config().dead_letter.clone();
+        if dead_letter.is_some_and(|p| p.dead_letter_queue_id == *queue_id) {
+            return HttpResponse::Conflict().json(JsonResponse::new(
+                None::<String>,
+                format!(
+                    "The queue {} is the dead-letter queue of {} and cannot be deleted",
+                    queue_id, other_id
+                ),
+            ));
+        }
+    }
+    let mut queue = queue.lock_owned().await;
+
+    let mut exchanges = data.get_exchanges().lock().await;
+    let mut bound_to = exchanges
+        .values()
+        .filter(|e| e.is_bound(queue_id))
+        .map(|e| e.id.to_owned())
+        .collect::<Vec<String>>();
+    if !bound_to.is_empty() && !post_data.cascade {
+        bound_to.sort();
+        return HttpResponse::Conflict().json(JsonResponse::new(
             None::<String>,
             format!(
-                "The max number of messages to send and receive at once {} is invalid",
-                post_data.max_batch
+                "The queue {} is bound to the exchanges {} - set cascade to unbind it from them",
+                queue_id,
+                bound_to.join(", ")
             ),
         ));
     }
-    if post_data.read_timeout <= 0 {
-        return HttpResponse::BadRequest().json(JsonResponse::new(
+
+    let record = Record::DeleteQueue {
+        queue_id: queue_id.to_owned(),
+    };
+    let wal = data.get_wal();
+    if wal.append(&record).is_err() || (queue.is_durable() && wal.sync().is_err()) {
+        return HttpResponse::InternalServerError().json(JsonResponse::new(
             None::<String>,
-            format!("The read timeout {} is invalid", post_data.read_timeout),
+            "Something went wrong. Please try again.",
         ));
     }
-    let queue = Queue::new(
-        post_data.queue_id.to_owned(),
-        post_data.read_timeout,
-        post_data.max_batch,
-    );
-    let mut queues = data.get_queues().lock().await;
-    let queue_uuid = &queue.get_uuid();
-    match queues.entry(post_data.queue_id.to_owned()) {
-        Entry::Vacant(_) => {
-            queues.insert(post_data.queue_id.to_owned(), queue);
-            HttpResponse::Accepted().json(JsonResponse::new(queue_uuid, None::<String>))
+    queues.remove(queue_id);
+    for exchange in exchanges.values_mut() {
+        exchange.unbind_queue(queue_id);
+    }
+    // the deletion is already durable, files left behind are only logged
+    if let Err(e) = queue.destroy() {
+        eprintln!("Failed to remove the messages of queue {}: {}", queue_id, e);
+    }
+    HttpResponse::Accepted().json(JsonResponse::new(
+        format!("Successfully deleted queue {}", queue_id),
+        None::<String>,
+    ))
+}
+
+pub async fn purge_queue(
+    data: web::Data<AppState>,
+    post_data: web::Json<QueueRequest>,
+) -> HttpResponse {
+    let queue_id = &post_data.queue_id;
+    let queue = match data.get_queue(queue_id).await {
+        None => {
+            return HttpResponse::BadRequest().json(JsonResponse::new(
+                None::<String>,
+                format!("No queue with id {} was found", queue_id),
+            ))
         }
-        Entry::Occupied(_) => HttpResponse::Conflict().json(JsonResponse::new(
+        Some(q) => q,
+    };
+    let mut queue = queue.lock().await;
+
+    match queue.purge(data.get_wal()) {
+        Err(_) => HttpResponse::InternalServerError().json(JsonResponse::new(
             None::<String>,
-            format!("A queue with id {} already exists", post_data.queue_id),
+            "Something went wrong. Please try again.",
         )),
+        Ok(count) => HttpResponse::Accepted().json(JsonResponse::new(
+            format!(
+                "Successfully purged {} messages from queue {}",
+                count, queue_id
+            ),
+            None::<String>,
+        )),
+    }
+}
+
+pub async fn describe_queue(
+    data: web::Data<AppState>,
+    query_data: web::Query<QueueRequest>,
+) -> HttpResponse {
+    let queue_id = &query_data.queue_id;
+    let queue = match data.get_queue(queue_id).await {
+        None => {
+            return HttpResponse::BadRequest().json(JsonResponse::new(
+                None::<String>,
+                format!("No queue with id {} was found", queue_id),
+            ))
+        }
+        Some(q) => q,
+    };
+    let queue = queue.lock().await;
+
+    let description =
+        QueueDescription::new(queue.get_raw_uuid(), queue.get_config(), queue.get_stats());
+    HttpResponse::Accepted().json(JsonResponse::new(description, None::<String>))
+}
+
+// shows the messages in a queue a page at a time without receiving them, so
+// their visibility and receive counts stay as they are
+pub async fn browse_queue(
+    data: web::Data<AppState>,
+    query_data: web::Query<BrowseQueueRequest>,
+) -> HttpResponse {
+    let limit = query_data.limit.unwrap_or(DEFAULT_BROWSE_LIMIT);
+    if limit == 0 || limit > MAX_BROWSE_LIMIT {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!(
+                "The limit {} is invalid, it must be between 1 and {}",
+                limit, MAX_BROWSE_LIMIT
+            ),
+        ));
+    }
+    let queue_id = &query_data.queue_id;
+    let queue = match data.get_queue(queue_id).await {
+        None => {
+            return HttpResponse::BadRequest().json(JsonResponse::new(
+                None::<String>,
+                format!("No queue with id {} was found", queue_id),
+            ))
+        }
+        Some(q) => q,
+    };
+    let queue = queue.lock().await;
+
+    let offset = query_data.offset.unwrap_or(0);
+    let messages = match queue.browse(data.get_keys(), offset, limit) {
+        Ok(m) => m,
+        Err(_) => {
+            return HttpResponse::InternalServerError().json(JsonResponse::new(
+                None::<String>,
+                "Something went wrong. Please try again.",
+            ))
+        }
+    };
+    let next_offset =
+        Some(offset.saturating_add(messages.len())).filter(|n| *n < queue.get_message_count());
+    HttpResponse::Accepted().json(JsonResponse::new(
+        BrowseQueueResponse {
+            messages,
+            next_offset,
+        },
+        None::<String>,
+    ))
+}
+
+// drops or dead-letters every expired message, a batch at a time so the
+// queues are never locked for long. A queue that fails is logged and skipped
+// until the next sweep, so it does not stop expiry on the others
+pub async fn remove_expired_messages(data: &AppState) -> usize {
+    let queue_ids = data
+        .get_queues()
+        .read()
+        .await
+        .keys()
+        .cloned()
+        .collect::<Vec<String>>();
+    let now = Utc::now();
+    let mut removed = 0;
+    for queue_id in queue_ids {
+        loop {
+            let (mut queue, mut dead_letter_queue) =
+                match data.lock_queue_with_dead_letters(&queue_id).await {
+                    None => break,
+                    Some(q) => q,
+                };
+            let count = match queue.remove_expired(
+                data.get_wal(),
+                now,
+                SWEEP_BATCH_SIZE,
+                dead_letter_queue.as_deref_mut(),
+            ) {
+                Ok(count) => count,
+                Err(e) => {
+                    eprintln!(
+                        "Failed to remove expired messages from queue {}: {:?}",
+                        queue_id, e
+                    );
+                    break;
+                }
+            };
+            removed += count;
+            if count < SWEEP_BATCH_SIZE {
+                break;
+            }
+        }
     }
+    removed
 }
 
 pub async fn list_queues(data: web::Data<AppState>) -> HttpResponse {
-    let queues = data.get_queues().lock().await;
+    let queues = data.get_queues().read().await;
     let queue_uuids = queues.keys().collect::<Vec<&String>>();
     HttpResponse::Accepted().json(JsonResponse::new(queue_uuids, None::<String>))
 }
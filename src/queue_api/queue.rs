@@ -1,36 +1,127 @@
-use std::error::Error;
-
-use aes_gcm::aead::Aead;
-use aes_gcm::aes::Aes256;
-use aes_gcm::AesGcm;
-use chrono::{DateTime, Duration, Utc};
-use futures::lock::MutexGuard;
-use uuid::Uuid;
-
-use aes_gcm::aes::cipher::typenum::bit::{B0, B1};
-use aes_gcm::aes::cipher::typenum::{UInt, UTerm};
+use aes_gcm::aead::{Aead, Nonce};
 use aes_gcm::{
-    aead::{generic_array::GenericArray, AeadCore, OsRng},
+    aead::{AeadCore, OsRng},
     Aes256Gcm,
 };
+use chrono::{DateTime, Duration, Utc};
+use deduplication::{content_hash, DeduplicationCache};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use store::{QueueStore, StorageType};
+use tokio::sync::Notify;
+use uuid::Uuid;
 
-#[derive(Debug)]
-pub struct EncryptionError;
+use crate::keys::KeyRing;
+use crate::persistence::wal::Wal;
+use crate::persistence::Record;
+
+mod deduplication;
+pub(crate) mod store;
+
+// how long a deduplication id is remembered unless the queue says otherwise
+pub const DEFAULT_DEDUPLICATION_WINDOW_SECONDS: u32 = 300;
 
 #[derive(Debug)]
+pub enum QueueError {
+    EncryptionError,
+    PersistenceError,
+    QueueDeleted,       // the queue was deleted while waiting for its lock
+    MissingGroupId,     // messages sent to a FIFO queue need a group id
+    MessageNotFound,    // the receipt handle is malformed or its message is gone
+    StaleReceiptHandle, // the lease the receipt handle was issued for ran out
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     id: String,
     content: Vec<u8>,
     last_read: Option<DateTime<Utc>>,
     uuid: Uuid,
-    nonce: GenericArray<u8, UInt<UInt<UInt<UInt<UTerm, B1>, B1>, B0>, B0>>,
+    #[serde(with = "nonce_bytes")]
+    nonce: Nonce<Aes256Gcm>,
+    key_id: String, // the key the content was encrypted with
+    #[serde(default)]
+    receive_count: u32, // how many times the message was handed to a consumer
+    #[serde(default)]
+    dead_letter: Option<DeadLetterInfo>, // set once the message is moved to a dead-letter queue
+    #[serde(default)]
+    visible_at: Option<DateTime<Utc>>, // delayed and leased messages are not handed out before this
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>, // the message is dropped once this passes
+    #[serde(default)]
+    receipt: Option<Uuid>, // issued with the current lease, needed to delete the message
+    #[serde(default = "Utc::now")]
+    sent_at: DateTime<Utc>, // when the message was first added to a queue
+    #[serde(default)]
+    group_id: Option<String>, // in a FIFO queue, messages of a group are handed out one at a time
+    #[serde(default)]
+    deduplication_id: Option<String>, // repeated sends with this id are dropped for a while
+    #[serde(default)]
+    priority: u8, // in a priority queue, higher priorities are handed out first
+    #[serde(default)]
+    attributes: BTreeMap<String, String>, // what headers exchanges route on
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum DeadLetterReason {
+    #[default]
+    MaxReceiveCount, // received too often without being deleted
+    Expired, // its time to live or the queue's retention period ran out
+}
+
+// where a dead-lettered message came from, why, and how often it was received there
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetterInfo {
+    pub source_queue_id: String,
+    pub receive_count: u32,
+    #[serde(default)]
+    pub reason: DeadLetterReason,
+}
+
+// what a lease changes about a message. Stores that persist their own
+// messages log it so visibility deadlines, receipt handles and receive counts
+// survive a restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lease {
+    pub last_read: Option<DateTime<Utc>>,
+    pub visible_at: Option<DateTime<Utc>>,
+    pub receive_count: u32,
+    pub receipt: Option<Uuid>,
+}
+
+// settings given with a single receive
+#[derive(Debug, Clone, Default)]
+pub struct ReceiveOptions {
+    pub max_messages: Option<u32>,       // capped at the queue's max batch
+    pub visibility_timeout: Option<u32>, // overrides the queue's read timeout
+}
+
+// settings given with a single message
+#[derive(Debug, Clone, Default)]
+pub struct MessageOptions {
+    pub delay_seconds: Option<u32>,       // overrides the queue's delay
+    pub ttl_seconds: Option<u32>,         // how long the message is kept at most
+    pub group_id: Option<String>,         // needed by FIFO queues, ignored by the rest
+    pub deduplication_id: Option<String>, // overrides the content hash of the message
+    pub priority: Option<u8>, // capped at the queue's max priority, ignored by queues without one
+    pub attributes: BTreeMap<String, String>,
 }
 
 impl Message {
     pub fn new(
         id: String,
         content: Vec<u8>,
-        nonce: GenericArray<u8, UInt<UInt<UInt<UInt<UTerm, B1>, B1>, B0>, B0>>,
+        nonce: Nonce<Aes256Gcm>,
+        key_id: String,
+        visible_at: Option<DateTime<Utc>>,
+        expires_at: Option<DateTime<Utc>>,
+        group_id: Option<String>,
     ) -> Self {
         Message {
             id,
@@ -38,6 +129,17 @@ impl Message {
             last_read: None,
             uuid: Uuid::new_v4(),
             nonce,
+            key_id,
+            receive_count: 0,
+            dead_letter: None,
+            visible_at,
+            expires_at,
+            receipt: None,
+            sent_at: Utc::now(),
+            group_id,
+            deduplication_id: None,
+            priority: 0,
+            attributes: BTreeMap::new(),
         }
     }
 
@@ -45,30 +147,133 @@ impl Message {
         self.uuid.to_string()
     }
 
-    pub fn uuid_matches(&self, uuid: &String) -> bool {
-        self.uuid.to_string() == *uuid
+    pub fn get_raw_uuid(&self) -> Uuid {
+        self.uuid
     }
 
-    pub fn is_visible(&self, read_timeout: u32) -> bool {
-        match self.last_read {
-            None => true,
-            Some(dt) => {
-                let now = Utc::now();
-                now - dt > Duration::seconds(read_timeout as i64)
-            }
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    pub fn get_dead_letter(&self) -> Option<&DeadLetterInfo> {
+        self.dead_letter.as_ref()
+    }
+
+    pub fn get_expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+
+    pub fn get_group_id(&self) -> Option<&str> {
+        self.group_id.as_deref()
+    }
+
+    pub fn get_priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|dt| dt <= now)
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.hidden_until(Utc::now()).is_none()
+    }
+
+    // whether a consumer received the message and may still delete it
+    pub fn is_leased(&self) -> bool {
+        self.last_read.is_some() && !self.is_visible()
+    }
+
+    // gives the message a new lease. Receipt handles from earlier leases stop
+    // working
+    pub fn lease(&mut self, now: DateTime<Utc>, visible_at: DateTime<Utc>) {
+        self.last_read = Some(now);
+        self.visible_at = Some(visible_at);
+        self.receive_count += 1;
+        self.receipt = Some(Uuid::new_v4());
+    }
+
+    pub fn get_lease(&self) -> Lease {
+        Lease {
+            last_read: self.last_read,
+            visible_at: self.visible_at,
+            receive_count: self.receive_count,
+            receipt: self.receipt,
         }
     }
+
+    pub fn set_lease(&mut self, lease: Lease) {
+        self.last_read = lease.last_read;
+        self.visible_at = lease.visible_at;
+        self.receive_count = lease.receive_count;
+        self.receipt = lease.receipt;
+    }
+
+    // checks a receipt handle against the current lease
+    fn check_receipt(&self, receipt: &Uuid) -> Result<(), QueueError> {
+        if self.receipt.as_ref() == Some(receipt) && self.is_leased() {
+            Ok(())
+        } else {
+            Err(QueueError::StaleReceiptHandle)
+        }
+    }
+
+    // when the message can be handed out again, if it is delayed or leased
+    pub fn hidden_until(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.visible_at.filter(|dt| *dt >= now)
+    }
+}
+
+// nonces are stored as plain bytes so messages can be written to disk
+mod nonce_bytes {
+    use aes_gcm::aead::Nonce;
+    use aes_gcm::Aes256Gcm;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(nonce: &Nonce<Aes256Gcm>, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_bytes(nonce.as_slice())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Nonce<Aes256Gcm>, D::Error> {
+        let bytes = Vec::<u8>::deserialize(d)?;
+        if bytes.len() != 12 {
+            return Err(D::Error::custom(format!(
+                "nonce must be 12 bytes, found {}",
+                bytes.len()
+            )));
+        }
+        Ok(*Nonce::<Aes256Gcm>::from_slice(&bytes))
+    }
 }
 
 pub struct DecryptedMessage {
     id: String,
     content: String,
     uuid: Uuid,
+    receipt_handle: String,
+    receive_count: u32,
+    dead_letter: Option<DeadLetterInfo>,
+    group_id: Option<String>,
+    attributes: BTreeMap<String, String>,
 }
 
 impl DecryptedMessage {
-    pub fn new(id: String, content: String, uuid: Uuid) -> Self {
-        DecryptedMessage { id, content, uuid }
+    pub fn new(message: Message, content: String) -> Self {
+        DecryptedMessage {
+            receipt_handle: receipt_handle(&message),
+            id: message.id,
+            content,
+            uuid: message.uuid,
+            receive_count: message.receive_count,
+            dead_letter: message.dead_letter,
+            group_id: message.group_id,
+            attributes: message.attributes,
+        }
     }
     pub fn get_uuid(&self) -> String {
         self.uuid.to_string()
@@ -81,108 +286,779 @@ impl DecryptedMessage {
     pub fn get_content(&self) -> String {
         (*self.content).to_owned()
     }
+
+    pub fn get_receive_count(&self) -> u32 {
+        self.receive_count
+    }
+
+    pub fn get_receipt_handle(&self) -> String {
+        self.receipt_handle.to_owned()
+    }
+
+    pub fn get_dead_letter(&self) -> Option<DeadLetterInfo> {
+        self.dead_letter.clone()
+    }
+
+    pub fn get_group_id(&self) -> Option<String> {
+        self.group_id.clone()
+    }
+
+    pub fn get_attributes(&self) -> BTreeMap<String, String> {
+        self.attributes.clone()
+    }
+}
+
+// messages received more than `max_receive_count` times are moved to the
+// dead-letter queue instead of being handed out again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetterPolicy {
+    pub max_receive_count: u32,
+    pub dead_letter_queue_id: String,
+}
+
+// messages carry a priority from 0 up to `max_priority` and the highest
+// priority visible messages are handed out first. With `aging_seconds` set, a
+// waiting message counts as one priority higher for every `aging_seconds` it
+// waited, so low priorities are not starved by a steady stream of high ones
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PriorityPolicy {
+    pub max_priority: u8,
+    pub aging_seconds: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueConfig {
+    pub id: String,           // user id for queue - also unique
+    pub read_timeout: u32,    // the amount of time a message is hidden from consumers
+    pub max_batch: u32,       // the max number of messages to insert and return at once
+    pub storage: StorageType, // where the messages are kept
+    #[serde(default)]
+    pub dead_letter: Option<DeadLetterPolicy>, // where poison messages go
+    #[serde(default)]
+    pub delay_seconds: u32, // how long new messages stay hidden unless they say otherwise
+    #[serde(default)]
+    pub retention_seconds: Option<u32>, // how long messages are kept at most
+    #[serde(default)]
+    pub dead_letter_expired: bool, // whether expired messages go to the dead-letter queue
+    #[serde(default)]
+    pub fifo: bool, // messages of a group are handed out in order, one at a time
+    #[serde(default = "default_deduplication_window")]
+    pub deduplication_window_seconds: u32, // how long repeated sends are dropped for
+    #[serde(default)]
+    pub content_based_deduplication: bool, // messages without a deduplication id use their content's hash
+    #[serde(default)]
+    pub priority: Option<PriorityPolicy>, // makes the queue a priority queue
+}
+
+fn default_deduplication_window() -> u32 {
+    DEFAULT_DEDUPLICATION_WINDOW_SECONDS
+}
+
+// message counts of a queue, as of when they were taken
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueStats {
+    pub total: usize,
+    pub visible: usize,   // messages that can be received now
+    pub in_flight: usize, // messages received and not deleted or timed out yet
+    pub delayed: usize,   // messages that were not visible yet or wait behind their group
+    pub oldest_message_age_seconds: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum MessageState {
+    Visible,  // can be received now
+    InFlight, // received and not deleted or timed out yet
+    Delayed,  // not visible yet
+    Waiting,  // held back until the messages before it in its group are deleted
+}
+
+// a message as seen by someone looking through the queue, not receiving it
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BrowsedMessage {
+    message_id: String,
+    content: String,
+    uuid: Uuid,
+    state: MessageState,
+    visible_at: Option<DateTime<Utc>>, // when an in-flight or delayed message becomes visible
+    receive_count: u32,
+    sent_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    dead_letter: Option<DeadLetterInfo>,
+    group_id: Option<String>,
+    priority: u8,
+    attributes: BTreeMap<String, String>,
 }
 
-#[derive(Debug)]
 pub struct Queue {
-    queue: Vec<Message>, // the actual queue
-    read_timeout: u32,   // the amount of time a message is hidden from consumers
-    size: u32,           // should always be the same as queue.len()
-    uuid: Uuid,          // unique uuid
-    id: String,          // user id for queue - also unique
-    max_batch: u32,      // the max number of messages to insert and return at once
+    store: Box<dyn QueueStore>,        // the actual queue
+    uuid: Uuid,                        // unique uuid
+    config: QueueConfig,               // user supplied settings
+    notify: Arc<Notify>,               // wakes consumers waiting for messages
+    deleted: bool,                     // set once the queue is deleted, refuses any changes after
+    deduplication: DeduplicationCache, // recently sent deduplication ids
 }
 
 impl Queue {
-    pub fn new(id: String, read_timeout: u32, max_batch: u32) -> Self {
-        Queue {
-            queue: vec![],
-            read_timeout,
-            size: 0,
-            uuid: Uuid::new_v4(),
-            id,
-            max_batch,
+    pub fn new(config: QueueConfig, data_dir: &Path) -> io::Result<Self> {
+        Queue::restore(config, Uuid::new_v4(), data_dir)
+    }
+
+    pub fn restore(config: QueueConfig, uuid: Uuid, data_dir: &Path) -> io::Result<Self> {
+        let mut queue = Queue {
+            store: store::open(config.storage, config.priority.clone(), data_dir, &uuid)?,
+            uuid,
+            config,
+            notify: Arc::new(Notify::new()),
+            deleted: false,
+            deduplication: DeduplicationCache::new(),
+        };
+        // durable stores come back with their messages
+        let messages = queue.store.messages().cloned().collect::<Vec<Message>>();
+        for message in messages.iter() {
+            queue.remember_deduplication_id(message);
         }
+        Ok(queue)
     }
 
     pub fn get_uuid(&self) -> String {
         self.uuid.to_string()
     }
 
-    pub fn get_id(&self) -> String {
-        self.id.to_owned()
+    pub fn get_raw_uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    pub fn get_config(&self) -> &QueueConfig {
+        &self.config
+    }
+
+    pub fn get_messages(&self) -> Box<dyn Iterator<Item = &Message> + '_> {
+        self.store.messages()
+    }
+
+    pub fn get_message_count(&self) -> usize {
+        self.store.count()
+    }
+
+    pub fn is_durable(&self) -> bool {
+        self.store.is_durable()
+    }
+
+    // notified whenever a message is added. Waiters should register before
+    // the queue lock is released so no message slips by unnoticed
+    pub fn get_notify(&self) -> Arc<Notify> {
+        self.notify.clone()
+    }
+
+    pub fn next_visible_at(&self) -> Option<DateTime<Utc>> {
+        self.store.next_visible_at()
+    }
+
+    pub fn get_stats(&self) -> QueueStats {
+        let now = Utc::now();
+        let mut stats = QueueStats {
+            total: 0,
+            visible: 0,
+            in_flight: 0,
+            delayed: 0,
+            oldest_message_age_seconds: None,
+        };
+        let mut oldest = None;
+        let mut groups = HashSet::new();
+        for message in self.store.messages() {
+            stats.total += 1;
+            let waiting = message.group_id.as_ref().is_some_and(|g| !groups.insert(g));
+            if message.is_leased() {
+                stats.in_flight += 1;
+            } else if waiting || message.hidden_until(now).is_some() {
+                stats.delayed += 1;
+            } else {
+                stats.visible += 1;
+            }
+            oldest =
+                Some(oldest.map_or(message.sent_at, |dt: DateTime<Utc>| dt.min(message.sent_at)));
+        }
+        stats.oldest_message_age_seconds = oldest.map(|dt| (now - dt).num_seconds());
+        stats
+    }
+
+    // up to `limit` messages in the order they arrived, skipping the first
+    // `offset`. Unlike dispatch nothing about the messages is changed
+    pub fn browse(
+        &self,
+        keys: &KeyRing,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<BrowsedMessage>, QueueError> {
+        let now = Utc::now();
+        let mut messages = vec![];
+        // messages behind an earlier message of their group are waiting, so
+        // the groups of the skipped messages count too
+        let mut groups = HashSet::new();
+        for (i, message) in self
+            .store
+            .messages()
+            .enumerate()
+            .take(offset.saturating_add(limit))
+        {
+            let waiting = message.group_id.as_ref().is_some_and(|g| !groups.insert(g));
+            if i < offset {
+                continue;
+            }
+            let hidden_until = message.hidden_until(now);
+            let state = if message.is_leased() {
+                MessageState::InFlight
+            } else if waiting {
+                MessageState::Waiting
+            } else if hidden_until.is_some() {
+                MessageState::Delayed
+            } else {
+                MessageState::Visible
+            };
+            messages.push(BrowsedMessage {
+                message_id: message.id.to_owned(),
+                content: decrypt_content(keys, message)?,
+                uuid: message.uuid,
+                state,
+                visible_at: hidden_until,
+                receive_count: message.receive_count,
+                sent_at: message.sent_at,
+                expires_at: message.expires_at,
+                dead_letter: message.dead_letter.clone(),
+                group_id: message.group_id.clone(),
+                priority: message.priority,
+                attributes: message.attributes.clone(),
+            });
+        }
+        Ok(messages)
+    }
+
+    // drops every message in the queue and returns how many there were
+    pub fn purge(&mut self, wal: &Wal) -> Result<usize, QueueError> {
+        if self.deleted {
+            return Err(QueueError::QueueDeleted);
+        }
+        let count = self.store.count();
+        if !self.store.is_durable() {
+            let record = Record::PurgeQueue {
+                queue_id: self.config.id.to_owned(),
+            };
+            if wal.append(&record).is_err() {
+                return Err(QueueError::PersistenceError);
+            }
+        }
+        match self.store.clear() {
+            Ok(_) => Ok(count),
+            Err(_) => Err(QueueError::PersistenceError),
+        }
+    }
+
+    // replaces the settings of the queue. Messages already in the queue keep
+    // the delay and retention they were sent with, but are ranked again when
+    // the priority policy changes
+    pub fn update_config(&mut self, wal: &Wal, config: QueueConfig) -> Result<(), QueueError> {
+        if self.deleted {
+            return Err(QueueError::QueueDeleted);
+        }
+        let record = Record::UpdateQueue {
+            queue_id: self.config.id.to_owned(),
+            config: config.clone(),
+        };
+        let durable = self.store.is_durable();
+        if wal.append(&record).is_err() || (durable && wal.sync().is_err()) {
+            return Err(QueueError::PersistenceError);
+        }
+        self.set_config(config);
+        Ok(())
+    }
+
+    // used when replaying the log, the update is already durable
+    pub fn set_config(&mut self, config: QueueConfig) {
+        if config.priority != self.config.priority {
+            self.store.set_priority(config.priority.clone());
+        }
+        self.config = config;
+    }
+
+    // used when replaying the log, the purge is already durable
+    pub fn forget_messages(&mut self) -> io::Result<()> {
+        self.store.clear()
+    }
+
+    // called once the queue is out of the queue map and its deletion logged.
+    // Anyone still holding the queue is refused from here on, and consumers
+    // waiting for messages are woken up so they notice
+    pub fn destroy(&mut self) -> io::Result<()> {
+        self.deleted = true;
+        self.notify.notify_waiters();
+        self.store.destroy()
     }
 
     pub fn add_to_queue(
         &mut self,
-        cipher: &MutexGuard<AesGcm<Aes256, UInt<UInt<UInt<UInt<UTerm, B1>, B1>, B0>, B0>>>,
+        keys: &KeyRing,
+        wal: &Wal,
         id: String,
         content: String,
-    ) -> Result<String, EncryptionError> {
+        options: &MessageOptions,
+    ) -> Result<String, QueueError> {
+        if self.config.fifo && options.group_id.is_none() {
+            return Err(QueueError::MissingGroupId);
+        }
+        let group_id = options.group_id.clone().filter(|_| self.config.fifo);
+        let deduplication_id = match &options.deduplication_id {
+            Some(id) => Some(id.to_owned()),
+            None if self.config.content_based_deduplication => Some(content_hash(&content)),
+            None => None,
+        };
+        // a repeated send gets the uuid of the message it repeats
+        if let Some(deduplication_id) = &deduplication_id {
+            if let Some(uuid) = self.deduplication.get(deduplication_id, Utc::now()) {
+                return Ok(uuid.to_string());
+            }
+        }
         let nonce = Aes256Gcm::generate_nonce(&mut OsRng); // 96-bits; unique per message
-        let ciphered_content = match cipher.encrypt(&nonce, content.as_ref()) {
+        let ciphered_content = match keys.get_current().encrypt(&nonce, content.as_ref()) {
             Ok(s) => s,
-            Err(_) => return Err(EncryptionError),
+            Err(_) => return Err(QueueError::EncryptionError),
+        };
+        let key_id = keys.get_current_id().to_owned();
+        let delay_seconds = options.delay_seconds.unwrap_or(self.config.delay_seconds);
+        let visible_at = match delay_seconds {
+            0 => None,
+            delay => Some(Utc::now() + Duration::seconds(delay as i64)),
         };
-        let message = Message::new(id, ciphered_content, nonce);
+        let now = Utc::now();
+        let expires_at = [options.ttl_seconds, self.config.retention_seconds]
+            .into_iter()
+            .flatten()
+            .map(|secs| now + Duration::seconds(secs as i64))
+            .min();
+        let mut message = Message::new(
+            id,
+            ciphered_content,
+            nonce,
+            key_id,
+            visible_at,
+            expires_at,
+            group_id,
+        );
+        message.deduplication_id = deduplication_id;
+        message.priority = self.cap_priority(options.priority.unwrap_or(0));
+        message.attributes = options.attributes.clone();
         let uuid = message.get_uuid();
-        self.queue.push(message);
-        self.incr_size();
+        self.persist_message(wal, message.clone())?;
+        self.remember_deduplication_id(&message);
         Ok(uuid)
     }
 
+    // used when replaying the log, the message is already durable
+    pub fn restore_message(&mut self, message: Message) -> io::Result<()> {
+        self.remember_deduplication_id(&message);
+        self.store.push(message)
+    }
+
+    // the window starts when the message was sent, so ids recovered after a
+    // restart are only remembered for what is left of it. Ids of messages
+    // deleted before the restart are forgotten
+    fn remember_deduplication_id(&mut self, message: &Message) {
+        if let Some(deduplication_id) = &message.deduplication_id {
+            let window = Duration::seconds(self.config.deduplication_window_seconds as i64);
+            self.deduplication.insert(
+                deduplication_id.to_owned(),
+                message.uuid,
+                message.sent_at + window,
+            );
+        }
+    }
+
+    // hands out up to max_batch visible messages. Messages that were already
+    // received too often are moved to `dead_letter_queue` on the way, which
+    // has to be the queue named in the dead-letter policy
     pub fn dispatch(
         &mut self,
-        cipher: MutexGuard<AesGcm<Aes256, UInt<UInt<UInt<UInt<UTerm, B1>, B1>, B0>, B0>>>,
-    ) -> Result<Vec<DecryptedMessage>, EncryptionError> {
-        if self.size == 0 {
+        keys: &KeyRing,
+        wal: &Wal,
+        options: &ReceiveOptions,
+        mut dead_letter_queue: Option<&mut Queue>,
+    ) -> Result<Vec<DecryptedMessage>, QueueError> {
+        let max_batch = match options.max_messages {
+            None => self.config.max_batch,
+            Some(max_messages) => max_messages.min(self.config.max_batch),
+        } as usize;
+        let visibility_timeout = options
+            .visibility_timeout
+            .unwrap_or(self.config.read_timeout);
+        if self.deleted {
             return Ok(vec![]);
         }
-        let mut messages_to_dispatch = vec![];
-        for message in self.queue.iter_mut() {
-            if message.is_visible(self.read_timeout) {
-                // uncipher the message
-                let unciphered_content =
-                    match cipher.decrypt(&message.nonce, message.content.as_ref()) {
-                        Ok(s) => s,
-                        Err(_) => return Err(EncryptionError),
-                    };
-                let content = match String::from_utf8(unciphered_content) {
-                    Ok(s) => s,
-                    Err(_) => return Err(EncryptionError),
-                };
-
-                let decrypted_message =
-                    DecryptedMessage::new(message.id.clone(), content, message.uuid);
-                message.last_read = Some(Utc::now());
-                messages_to_dispatch.push(decrypted_message);
-            }
-            if messages_to_dispatch.len() == self.max_batch as usize {
+        let policy = self.config.dead_letter.clone();
+        let mut handed_out = vec![];
+        while handed_out.len() < max_batch {
+            let leased = match self
+                .store
+                .lease(max_batch - handed_out.len(), visibility_timeout)
+            {
+                Ok(m) => m,
+                Err(_) => return Err(QueueError::PersistenceError),
+            };
+            if leased.is_empty() {
                 break;
             }
+            let now = Utc::now();
+            for message in leased {
+                // the lease that found the message expired or over its limit
+                // is not a delivery
+                let receive_count = message.receive_count - 1;
+                if message.is_expired(now) {
+                    self.expire_message(
+                        wal,
+                        message,
+                        receive_count,
+                        dead_letter_queue.as_deref_mut(),
+                    )?;
+                    continue;
+                }
+                if let (Some(policy), Some(dlq)) = (&policy, dead_letter_queue.as_deref_mut()) {
+                    if message.receive_count > policy.max_receive_count
+                        && dlq.config.id == policy.dead_letter_queue_id
+                    {
+                        self.move_to_dead_letter_queue(
+                            wal,
+                            message,
+                            receive_count,
+                            DeadLetterReason::MaxReceiveCount,
+                            dlq,
+                        )?;
+                        continue;
+                    }
+                }
+                handed_out.push(message);
+            }
+        }
+        // the receive counts have to survive a restart before the messages
+        // are handed out, or crashing consumers would never dead-letter them
+        self.log_leases(wal, handed_out.iter())?;
+        handed_out
+            .into_iter()
+            .map(|message| decrypt(keys, message))
+            .collect()
+    }
+
+    // deletes a received message, given the receipt handle of its current lease
+    pub fn rem_from_queue(
+        &mut self,
+        wal: &Wal,
+        receipt_handle: &str,
+    ) -> Result<Message, QueueError> {
+        let uuid = self.find_leased(receipt_handle)?;
+        match self.unpersist_message(wal, &uuid)? {
+            None => Err(QueueError::MessageNotFound),
+            Some(m) => Ok(m),
+        }
+    }
+
+    // drops or dead-letters up to `max` messages that expired by `now`, and
+    // returns how many there were
+    pub fn remove_expired(
+        &mut self,
+        wal: &Wal,
+        now: DateTime<Utc>,
+        max: usize,
+        mut dead_letter_queue: Option<&mut Queue>,
+    ) -> Result<usize, QueueError> {
+        let expired = self.store.expired(now, max);
+        for uuid in expired.iter() {
+            let message = match self.store.get(uuid) {
+                None => continue,
+                Some(m) => m.clone(),
+            };
+            let receive_count = message.receive_count;
+            self.expire_message(
+                wal,
+                message,
+                receive_count,
+                dead_letter_queue.as_deref_mut(),
+            )?;
+        }
+        Ok(expired.len())
+    }
+
+    pub fn get_message(&self, uuid: &Uuid) -> Option<&Message> {
+        self.store.get(uuid)
+    }
+
+    // moves a visible message to `destination` as if it had never been
+    // received, keeping its message id and content. Messages that are gone or
+    // held by a consumer are left alone and false is returned
+    pub fn redrive_message(
+        &mut self,
+        wal: &Wal,
+        uuid: &Uuid,
+        destination: &mut Queue,
+    ) -> Result<bool, QueueError> {
+        let mut message = match self.store.get(uuid) {
+            Some(m) if m.is_visible() => m.clone(),
+            _ => return Ok(false),
+        };
+        message.receive_count = 0;
+        message.last_read = None;
+        message.receipt = None;
+        message.dead_letter = None;
+        message.visible_at = None;
+        message.expires_at = destination.retention_deadline(Utc::now());
+        message.group_id = message.group_id.filter(|_| destination.config.fifo);
+        message.priority = destination.cap_priority(message.priority);
+        message.deduplication_id = None;
+        // written to the destination first for the same reason as dead letters.
+        // A copy left there by a crash part way through a move is kept as is
+        if destination.store.get(uuid).is_none() {
+            destination.persist_message(wal, message)?;
+            self.sync_moved(wal, destination)?;
+        }
+        self.unpersist_message(wal, uuid)?;
+        Ok(true)
+    }
+
+    // moves the visibility deadline of a leased message to `visibility_timeout`
+    // seconds from now, a timeout of 0 hands it back to the queue right away
+    pub fn change_visibility(
+        &mut self,
+        wal: &Wal,
+        receipt_handle: &str,
+        visibility_timeout: u32,
+    ) -> Result<(), QueueError> {
+        let uuid = self.find_leased(receipt_handle)?;
+        let visible_at = Utc::now() + Duration::seconds(visibility_timeout as i64);
+        let mut message = self.store.get(&uuid).unwrap().clone();
+        let previous = message.visible_at.replace(visible_at);
+        self.log_leases(wal, std::iter::once(&message))?;
+        if self.store.set_visible_at(&uuid, visible_at).is_err() {
+            return Err(QueueError::PersistenceError);
+        }
+        // long polls sleep until the earliest deadline they saw, which may
+        // have been this message's old one
+        if previous.is_none_or(|previous| visible_at < previous) {
+            self.notify.notify_waiters();
+        }
+        Ok(())
+    }
+
+    // the uuid of the message a receipt handle is for, as long as the lease
+    // it was issued with is still current
+    fn find_leased(&self, receipt_handle: &str) -> Result<Uuid, QueueError> {
+        let (uuid, receipt) = match parse_receipt_handle(receipt_handle) {
+            None => return Err(QueueError::MessageNotFound),
+            Some(r) => r,
+        };
+        match self.store.get(&uuid) {
+            None => Err(QueueError::MessageNotFound),
+            Some(m) => m.check_receipt(&receipt).map(|_| uuid),
+        }
+    }
+
+    // used when replaying the log, the lease is already durable
+    pub fn restore_lease(&mut self, uuid: &Uuid, lease: Lease) -> io::Result<()> {
+        self.store.set_lease(uuid, lease)?;
+        Ok(())
+    }
+
+    // drops a message regardless of its visibility, used when replaying the log
+    pub fn forget_message(&mut self, uuid: &Uuid) -> io::Result<Option<Message>> {
+        self.store.delete(uuid)
+    }
+
+    // the priority a message gets in this queue, queues without a priority
+    // policy treat every message the same
+    fn cap_priority(&self, priority: u8) -> u8 {
+        self.config
+            .priority
+            .as_ref()
+            .map_or(0, |p| priority.min(p.max_priority))
+    }
+
+    // when messages added now run past the queue's retention period
+    fn retention_deadline(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.config
+            .retention_seconds
+            .map(|secs| now + Duration::seconds(secs as i64))
+    }
+
+    // expired messages are dead-lettered when the queue asks for it and the
+    // dead-letter queue was passed in, and dropped otherwise
+    fn expire_message(
+        &mut self,
+        wal: &Wal,
+        message: Message,
+        receive_count: u32,
+        dead_letter_queue: Option<&mut Queue>,
+    ) -> Result<(), QueueError> {
+        if self.config.dead_letter_expired {
+            if let (Some(policy), Some(dlq)) = (&self.config.dead_letter, dead_letter_queue) {
+                if dlq.config.id == policy.dead_letter_queue_id {
+                    return self.move_to_dead_letter_queue(
+                        wal,
+                        message,
+                        receive_count,
+                        DeadLetterReason::Expired,
+                        dlq,
+                    );
+                }
+            }
+        }
+        self.unpersist_message(wal, &message.uuid)?;
+        Ok(())
+    }
+
+    // the dead-letter copy is made durable before the original is dropped, so
+    // a crash in between can only duplicate the message, never lose it
+    fn move_to_dead_letter_queue(
+        &mut self,
+        wal: &Wal,
+        message: Message,
+        receive_count: u32,
+        reason: DeadLetterReason,
+        dead_letter_queue: &mut Queue,
+    ) -> Result<(), QueueError> {
+        let uuid = message.uuid;
+        let mut dead_letter = message;
+        dead_letter.dead_letter = Some(DeadLetterInfo {
+            source_queue_id: self.config.id.to_owned(),
+            receive_count,
+            reason,
+        });
+        dead_letter.receive_count = 0;
+        dead_letter.last_read = None;
+        dead_letter.receipt = None;
+        dead_letter.visible_at = None;
+        dead_letter.expires_at = dead_letter_queue.retention_deadline(Utc::now());
+        dead_letter.group_id = dead_letter
+            .group_id
+            .filter(|_| dead_letter_queue.config.fifo);
+        dead_letter.priority = dead_letter_queue.cap_priority(dead_letter.priority);
+        dead_letter.deduplication_id = None;
+        dead_letter_queue.persist_message(wal, dead_letter)?;
+        self.sync_moved(wal, dead_letter_queue)?;
+        self.unpersist_message(wal, &uuid)?;
+        Ok(())
+    }
+
+    // a copy moved into a queue without a durable store is only in the log,
+    // which may not be synced yet. A durable store drops the original for good
+    // right away, so the log is synced first. Drops from other stores are
+    // logged after the copy and cannot outlive it
+    fn sync_moved(&self, wal: &Wal, destination: &Queue) -> Result<(), QueueError> {
+        if self.store.is_durable() && !destination.store.is_durable() && wal.sync().is_err() {
+            return Err(QueueError::PersistenceError);
         }
-        Ok(messages_to_dispatch)
+        Ok(())
     }
 
-    pub fn rem_from_queue(&mut self, uuid: &String) -> Option<Message> {
-        if self.size == 0 {
-            return None;
+    // durable stores persist their own writes, everything else goes through
+    // the log first so an acknowledged message is never lost
+    fn persist_message(&mut self, wal: &Wal, message: Message) -> Result<(), QueueError> {
+        if self.deleted {
+            return Err(QueueError::QueueDeleted);
         }
-        for (idx, message) in self.queue.iter().enumerate() {
-            if message.uuid_matches(uuid) && !message.is_visible(self.read_timeout) {
-                let message_to_return = self.queue.remove(idx);
-                self.decr_size();
-                return Some(message_to_return);
+        if !self.store.is_durable() {
+            let record = Record::AddMessage {
+                queue_id: self.config.id.to_owned(),
+                message: message.clone(),
+            };
+            if wal.append(&record).is_err() {
+                return Err(QueueError::PersistenceError);
             }
         }
-        None
+        if self.store.push(message).is_err() {
+            return Err(QueueError::PersistenceError);
+        }
+        self.notify.notify_waiters();
+        Ok(())
     }
 
-    fn incr_size(&mut self) {
-        self.size += 1;
+    // durable stores log leases on their own, everything else logs them here
+    fn log_leases<'a>(
+        &self,
+        wal: &Wal,
+        messages: impl Iterator<Item = &'a Message>,
+    ) -> Result<(), QueueError> {
+        if self.store.is_durable() {
+            return Ok(());
+        }
+        let records = messages
+            .map(|m| Record::LeaseMessage {
+                queue_id: self.config.id.to_owned(),
+                uuid: m.uuid,
+                lease: m.get_lease(),
+            })
+            .collect::<Vec<Record>>();
+        if wal.append_all(&records).is_err() {
+            return Err(QueueError::PersistenceError);
+        }
+        Ok(())
     }
 
-    fn decr_size(&mut self) {
-        self.size -= 1;
+    fn unpersist_message(&mut self, wal: &Wal, uuid: &Uuid) -> Result<Option<Message>, QueueError> {
+        if self.deleted {
+            return Err(QueueError::QueueDeleted);
+        }
+        if !self.store.is_durable() {
+            let record = Record::RemoveMessage {
+                queue_id: self.config.id.to_owned(),
+                uuid: *uuid,
+            };
+            if wal.append(&record).is_err() {
+                return Err(QueueError::PersistenceError);
+            }
+        }
+        let message = match self.store.delete(uuid) {
+            Ok(m) => m,
+            Err(_) => return Err(QueueError::PersistenceError),
+        };
+        // the next message of the group may be handed out now
+        if message.as_ref().is_some_and(|m| m.group_id.is_some()) {
+            self.notify.notify_waiters();
+        }
+        Ok(message)
+    }
+}
+
+// receipt handles are opaque to consumers, they hold the message uuid and
+// the receipt of the lease they were issued with
+fn receipt_handle(message: &Message) -> String {
+    let receipt = message.receipt.unwrap_or_default();
+    format!("{}{}", message.uuid.simple(), receipt.simple())
+}
+
+fn parse_receipt_handle(receipt_handle: &str) -> Option<(Uuid, Uuid)> {
+    if receipt_handle.len() != 64 || !receipt_handle.is_ascii() {
+        return None;
+    }
+    let uuid = Uuid::parse_str(&receipt_handle[..32]).ok()?;
+    let receipt = Uuid::parse_str(&receipt_handle[32..]).ok()?;
+    Some((uuid, receipt))
+}
+
+// uncipher the message with whichever key it was encrypted with
+fn decrypt(keys: &KeyRing, message: Message) -> Result<DecryptedMessage, QueueError> {
+    let content = decrypt_content(keys, &message)?;
+    Ok(DecryptedMessage::new(message, content))
+}
+
+fn decrypt_content(keys: &KeyRing, message: &Message) -> Result<String, QueueError> {
+    let cipher = match keys.get(&message.key_id) {
+        None => return Err(QueueError::EncryptionError),
+        Some(c) => c,
+    };
+    let unciphered_content = match cipher.decrypt(&message.nonce, message.content.as_ref()) {
+        Ok(s) => s,
+        Err(_) => return Err(QueueError::EncryptionError),
+    };
+    match String::from_utf8(unciphered_content) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(QueueError::EncryptionError),
     }
 }
@@ -0,0 +1,54 @@
+use std::collections::{BTreeSet, HashMap};
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+// the messages sent with a deduplication id, remembered until their window
+// runs out. Windows are kept in `expiries` by when they end so old ids can be
+// forgotten without scanning
+pub struct DeduplicationCache {
+    seen: HashMap<String, (Uuid, DateTime<Utc>)>,
+    expiries: BTreeSet<(DateTime<Utc>, String)>,
+}
+
+impl DeduplicationCache {
+    pub fn new() -> Self {
+        DeduplicationCache {
+            seen: HashMap::new(),
+            expiries: BTreeSet::new(),
+        }
+    }
+
+    // the message first sent with `deduplication_id`, if its window is still open
+    pub fn get(&mut self, deduplication_id: &str, now: DateTime<Utc>) -> Option<Uuid> {
+        self.forget_expired(now);
+        self.seen.get(deduplication_id).map(|(uuid, _)| *uuid)
+    }
+
+    pub fn insert(&mut self, deduplication_id: String, uuid: Uuid, until: DateTime<Utc>) {
+        if let Some((_, old_until)) = self.seen.insert(deduplication_id.clone(), (uuid, until)) {
+            self.expiries.remove(&(old_until, deduplication_id.clone()));
+        }
+        self.expiries.insert((until, deduplication_id));
+    }
+
+    fn forget_expired(&mut self, now: DateTime<Utc>) {
+        while let Some((until, _)) = self.expiries.first() {
+            if *until > now {
+                break;
+            }
+            let (_, deduplication_id) = self.expiries.pop_first().unwrap();
+            self.seen.remove(&deduplication_id);
+        }
+    }
+}
+
+// the deduplication id of a message sent to a queue with content-based
+// deduplication
+pub fn content_hash(content: &str) -> String {
+    Sha256::digest(content.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
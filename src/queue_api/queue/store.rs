@@ -0,0 +1,87 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{Lease, Message, PriorityPolicy};
+use disk::DiskStore;
+use memory::MemoryStore;
+
+mod disk;
+mod memory;
+
+#[allow(clippy::upper_case_acronyms)] // the variant names are the wire format
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum StorageType {
+    #[default]
+    MEMORY, // kept in memory, persisted through the write-ahead log
+    DISK, // written through to a file of its own on every change
+}
+
+// where the messages of a queue are kept
+pub trait QueueStore: Send {
+    fn push(&mut self, message: Message) -> io::Result<()>;
+
+    // hides up to `max` visible messages for `visibility_timeout` seconds,
+    // counts the receive and returns copies of them
+    fn lease(&mut self, max: usize, visibility_timeout: u32) -> io::Result<Vec<Message>>;
+
+    fn get(&self, uuid: &Uuid) -> Option<&Message>;
+
+    // hides a message until `visible_at`, or makes it visible right away if
+    // that has passed. Returns false if there is no such message
+    fn set_visible_at(&mut self, uuid: &Uuid, visible_at: DateTime<Utc>) -> io::Result<bool>;
+
+    // puts back a lease that was logged before a restart. Returns false if
+    // there is no such message
+    fn set_lease(&mut self, uuid: &Uuid, lease: Lease) -> io::Result<bool>;
+
+    // when the next leased or delayed message becomes visible
+    fn next_visible_at(&self) -> Option<DateTime<Utc>>;
+
+    // up to `max` messages that expired by `now`, soonest expired first
+    fn expired(&self, now: DateTime<Utc>, max: usize) -> Vec<Uuid>;
+
+    fn delete(&mut self, uuid: &Uuid) -> io::Result<Option<Message>>;
+
+    fn count(&self) -> usize;
+
+    // ranks the queued messages again under a new priority policy
+    fn set_priority(&mut self, priority: Option<PriorityPolicy>);
+
+    // drops every message
+    fn clear(&mut self) -> io::Result<()>;
+
+    // removes whatever the store keeps on disk, once its queue is deleted
+    fn destroy(&mut self) -> io::Result<()>;
+
+    fn messages(&self) -> Box<dyn Iterator<Item = &Message> + '_>;
+
+    // durable stores persist their own messages, so they are left out of the
+    // write-ahead log and snapshots
+    fn is_durable(&self) -> bool;
+}
+
+// visible messages are handed out highest priority first when the queue has
+// a priority policy, and in the order they arrived otherwise
+pub fn open(
+    storage: StorageType,
+    priority: Option<PriorityPolicy>,
+    data_dir: &Path,
+    uuid: &Uuid,
+) -> io::Result<Box<dyn QueueStore>> {
+    match storage {
+        StorageType::MEMORY => Ok(Box::new(MemoryStore::new(priority))),
+        StorageType::DISK => {
+            let dir = data_dir.join("queues");
+            fs::create_dir_all(&dir)?;
+            Ok(Box::new(DiskStore::open(
+                &dir.join(format!("{}.log", uuid)),
+                priority,
+            )?))
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::memory::MemoryStore;
+use super::QueueStore;
+use crate::persistence::wal::read_records;
+use crate::queue_api::queue::{Lease, Message, PriorityPolicy};
+
+// the file is rewritten once it holds this many records that no longer matter
+const COMPACT_THRESHOLD: usize = 1024;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+enum StoreRecord {
+    Put { message: Box<Message> },
+    Delete { uuid: Uuid },
+    Lease { uuid: Uuid, lease: Lease },
+}
+
+// an append-only file of puts, leases and deletes that is fsynced on every change.
+// The messages are also kept in memory so reads never touch the disk
+pub struct DiskStore {
+    inner: MemoryStore,
+    file: File,
+    path: PathBuf,
+    garbage: usize, // records in the file for messages that are gone
+}
+
+impl DiskStore {
+    pub fn open(path: &Path, priority: Option<PriorityPolicy>) -> io::Result<Self> {
+        let mut inner = MemoryStore::new(priority);
+        let mut garbage = 0;
+        for record in read_records::<StoreRecord>(path, true)? {
+            match record {
+                StoreRecord::Put { message } => inner.push(*message)?,
+                StoreRecord::Delete { uuid } => {
+                    inner.delete(&uuid)?;
+                    garbage += 2;
+                }
+                // only the latest lease of a message matters, and compaction
+                // writes it into the message
+                StoreRecord::Lease { uuid, lease } => {
+                    inner.set_lease(&uuid, lease)?;
+                    garbage += 1;
+                }
+            }
+        }
+        let created = !path.exists();
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        if created {
+            sync_parent(path)?;
+        }
+        Ok(DiskStore {
+            inner,
+            file,
+            path: path.to_path_buf(),
+            garbage,
+        })
+    }
+
+    fn append(&mut self, record: &StoreRecord) -> io::Result<()> {
+        self.append_all(std::slice::from_ref(record))
+    }
+
+    // writes the records with a single fsync
+    fn append_all(&mut self, records: &[StoreRecord]) -> io::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut lines = vec![];
+        for record in records {
+            serde_json::to_writer(&mut lines, record)?;
+            lines.push(b'\n');
+        }
+        self.file.write_all(&lines)?;
+        self.file.sync_data()
+    }
+
+    // logs the current lease of messages that were leased or had their
+    // visibility changed
+    fn append_leases<'a>(&mut self, uuids: impl Iterator<Item = &'a Uuid>) -> io::Result<()> {
+        let records = uuids
+            .filter_map(|uuid| {
+                self.inner.get(uuid).map(|m| StoreRecord::Lease {
+                    uuid: *uuid,
+                    lease: m.get_lease(),
+                })
+            })
+            .collect::<Vec<StoreRecord>>();
+        self.append_all(&records)?;
+        self.garbage += records.len();
+        self.compact_if_needed();
+        Ok(())
+    }
+
+    // the change that made the garbage is already durable, a failed
+    // compaction is retried later
+    fn compact_if_needed(&mut self) {
+        if self.garbage > COMPACT_THRESHOLD && self.garbage > self.inner.count() {
+            if let Err(e) = self.compact() {
+                eprintln!("Failed to compact {}: {}", self.path.display(), e);
+            }
+        }
+    }
+
+    // rewrites the file with only the messages that are still queued. The
+    // handle to the new file is kept, so appends continue where it ends, and
+    // the rename is synced so those appends can't land in a file a crash
+    // swaps back for the old one
+    fn compact(&mut self) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for message in self.inner.messages() {
+            let record = StoreRecord::Put {
+                message: Box::new(message.clone()),
+            };
+            serde_json::to_writer(&mut writer, &record)?;
+            writer.write_all(b"\n")?;
+        }
+        let file = writer.into_inner()?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        sync_parent(&self.path)?;
+        self.file = file;
+        self.garbage = 0;
+        Ok(())
+    }
+}
+
+// makes a new or renamed file's directory entry durable
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+impl QueueStore for DiskStore {
+    fn push(&mut self, message: Message) -> io::Result<()> {
+        self.append(&StoreRecord::Put {
+            message: Box::new(message.clone()),
+        })?;
+        self.inner.push(message)
+    }
+
+    // a lease that cannot be logged is not handed out. Its messages stay
+    // hidden in memory until the lease runs out
+    fn lease(&mut self, max: usize, visibility_timeout: u32) -> io::Result<Vec<Message>> {
+        let leased = self.inner.lease(max, visibility_timeout)?;
+        self.append_leases(leased.iter().map(|m| &m.uuid))?;
+        Ok(leased)
+    }
+
+    fn get(&self, uuid: &Uuid) -> Option<&Message> {
+        self.inner.get(uuid)
+    }
+
+    fn set_visible_at(&mut self, uuid: &Uuid, visible_at: DateTime<Utc>) -> io::Result<bool> {
+        if !self.inner.set_visible_at(uuid, visible_at)? {
+            return Ok(false);
+        }
+        self.append_leases(std::iter::once(uuid))?;
+        Ok(true)
+    }
+
+    fn set_lease(&mut self, uuid: &Uuid, lease: Lease) -> io::Result<bool> {
+        if !self.inner.set_lease(uuid, lease)? {
+            return Ok(false);
+        }
+        self.append_leases(std::iter::once(uuid))?;
+        Ok(true)
+    }
+
+    fn next_visible_at(&self) -> Option<DateTime<Utc>> {
+        self.inner.next_visible_at()
+    }
+
+    fn expired(&self, now: DateTime<Utc>, max: usize) -> Vec<Uuid> {
+        self.inner.expired(now, max)
+    }
+
+    fn delete(&mut self, uuid: &Uuid) -> io::Result<Option<Message>> {
+        if self.inner.get(uuid).is_none() {
+            return Ok(None);
+        }
+        self.append(&StoreRecord::Delete { uuid: *uuid })?;
+        self.garbage += 2;
+        let message = self.inner.delete(uuid)?;
+        self.compact_if_needed();
+        Ok(message)
+    }
+
+    fn count(&self) -> usize {
+        self.inner.count()
+    }
+
+    // the policy is part of the queue's settings, which are logged on their own
+    fn set_priority(&mut self, priority: Option<PriorityPolicy>) {
+        self.inner.set_priority(priority)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.inner.clear()?;
+        self.compact()
+    }
+
+    fn destroy(&mut self) -> io::Result<()> {
+        self.inner.clear()?;
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn messages(&self) -> Box<dyn Iterator<Item = &Message> + '_> {
+        self.inner.messages()
+    }
+
+    fn is_durable(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+
+use chrono::{DateTime, Duration, Utc};
+use uuid::Uuid;
+
+use super::QueueStore;
+use crate::queue_api::queue::{Lease, Message, PriorityPolicy};
+
+struct Entry {
+    message: Message,
+    deadline: Option<DateTime<Utc>>, // when the message becomes visible, if leased or delayed
+    rank: i64,     // where the message goes in `ready`, lower ranks are handed out first
+    waiting: bool, // behind an earlier message of its group, in neither `ready` nor `hidden`
+}
+
+// messages are numbered in the order they arrive. Visible messages wait in
+// `ready` by rank and then in that order while leased and delayed ones sit in `hidden` ordered
+// by when they become visible, so leasing and deleting never scan the queue.
+// Messages that expire are also kept in `expiries` by when they do.
+// Messages with a group are also kept in `groups`, and only the first of each
+// group is ever in `ready` or `hidden`. The rest wait until it is deleted
+pub struct MemoryStore {
+    messages: BTreeMap<u64, Entry>,
+    index: HashMap<Uuid, u64>,
+    ready: BTreeSet<(i64, u64)>,
+    hidden: BTreeSet<(DateTime<Utc>, u64)>,
+    expiries: BTreeSet<(DateTime<Utc>, u64)>,
+    groups: HashMap<String, BTreeSet<u64>>,
+    next_seq: u64,
+    priority: Option<PriorityPolicy>,
+}
+
+impl MemoryStore {
+    pub fn new(priority: Option<PriorityPolicy>) -> Self {
+        MemoryStore {
+            messages: BTreeMap::new(),
+            index: HashMap::new(),
+            ready: BTreeSet::new(),
+            hidden: BTreeSet::new(),
+            expiries: BTreeSet::new(),
+            groups: HashMap::new(),
+            next_seq: 0,
+            priority,
+        }
+    }
+
+    // puts messages whose lease or delay ran out back in line
+    fn expire_leases(&mut self, now: DateTime<Utc>) {
+        while let Some(&(deadline, seq)) = self.hidden.first() {
+            if deadline >= now {
+                break;
+            }
+            self.hidden.pop_first();
+            if let Some(entry) = self.messages.get_mut(&seq) {
+                entry.deadline = None;
+                self.ready.insert((entry.rank, seq));
+            }
+        }
+    }
+
+    // puts a message in `ready`, or in `hidden` if it is delayed
+    fn queue_up(&mut self, seq: u64) {
+        let entry = self.messages.get_mut(&seq).unwrap();
+        entry.waiting = false;
+        entry.deadline = entry.message.visible_at.filter(|dt| *dt > Utc::now());
+        match entry.deadline {
+            None => self.ready.insert((entry.rank, seq)),
+            Some(deadline) => self.hidden.insert((deadline, seq)),
+        };
+    }
+
+    // changes a message and moves it to wherever its new visibility deadline
+    // puts it. Returns false if there is no such message
+    fn update(&mut self, uuid: &Uuid, change: impl FnOnce(&mut Message)) -> bool {
+        let seq = match self.index.get(uuid) {
+            None => return false,
+            Some(seq) => *seq,
+        };
+        let entry = self.messages.get_mut(&seq).unwrap();
+        change(&mut entry.message);
+        if entry.waiting {
+            return true;
+        }
+        match entry.deadline {
+            None => self.ready.remove(&(entry.rank, seq)),
+            Some(deadline) => self.hidden.remove(&(deadline, seq)),
+        };
+        match entry.message.visible_at.filter(|dt| *dt > Utc::now()) {
+            Some(visible_at) => {
+                entry.deadline = Some(visible_at);
+                self.hidden.insert((visible_at, seq));
+            }
+            None => {
+                entry.deadline = None;
+                self.ready.insert((entry.rank, seq));
+            }
+        }
+        true
+    }
+}
+
+// without aging a message is ranked by its priority alone. With aging a
+// message's priority goes up by one every `aging_seconds` it waits, which
+// comes down to ranking it by when it was sent, moved earlier by
+// `aging_seconds` for every level of priority it has
+fn rank(policy: Option<&PriorityPolicy>, message: &Message) -> i64 {
+    let policy = match policy {
+        None => return 0,
+        Some(p) => p,
+    };
+    // messages sent before the max priority was lowered are capped too
+    let priority = message.get_priority().min(policy.max_priority) as i64;
+    match policy.aging_seconds {
+        None => -priority,
+        Some(aging_seconds) => {
+            message.sent_at.timestamp_millis() - priority * aging_seconds as i64 * 1000
+        }
+    }
+}
+
+impl QueueStore for MemoryStore {
+    fn push(&mut self, message: Message) -> io::Result<()> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.index.insert(message.uuid, seq);
+        if let Some(expires_at) = message.get_expires_at() {
+            self.expiries.insert((expires_at, seq));
+        }
+        let waiting = match message.get_group_id() {
+            None => false,
+            Some(group_id) => {
+                let group = self.groups.entry(group_id.to_owned()).or_default();
+                group.insert(seq);
+                group.len() > 1
+            }
+        };
+        let rank = rank(self.priority.as_ref(), &message);
+        self.messages.insert(
+            seq,
+            Entry {
+                message,
+                deadline: None,
+                rank,
+                waiting,
+            },
+        );
+        if !waiting {
+            self.queue_up(seq);
+        }
+        Ok(())
+    }
+
+    fn lease(&mut self, max: usize, visibility_timeout: u32) -> io::Result<Vec<Message>> {
+        let now = Utc::now();
+        self.expire_leases(now);
+        let timeout = Duration::seconds(visibility_timeout as i64);
+        let mut leased = vec![];
+        while leased.len() < max {
+            let seq = match self.ready.pop_first() {
+                None => break,
+                Some((_, seq)) => seq,
+            };
+            let entry = self.messages.get_mut(&seq).unwrap();
+            // a message whose visibility deadline has not passed yet waits
+            // until it does
+            if let Some(deadline) = entry.message.hidden_until(now) {
+                entry.deadline = Some(deadline);
+                self.hidden.insert((deadline, seq));
+                continue;
+            }
+            let deadline = now + timeout;
+            entry.message.lease(now, deadline);
+            entry.deadline = Some(deadline);
+            self.hidden.insert((deadline, seq));
+            leased.push(entry.message.clone());
+        }
+        Ok(leased)
+    }
+
+    fn get(&self, uuid: &Uuid) -> Option<&Message> {
+        let seq = self.index.get(uuid)?;
+        self.messages.get(seq).map(|e| &e.message)
+    }
+
+    fn set_visible_at(&mut self, uuid: &Uuid, visible_at: DateTime<Utc>) -> io::Result<bool> {
+        Ok(self.update(uuid, |message| message.visible_at = Some(visible_at)))
+    }
+
+    fn set_lease(&mut self, uuid: &Uuid, lease: Lease) -> io::Result<bool> {
+        Ok(self.update(uuid, |message| message.set_lease(lease)))
+    }
+
+    fn next_visible_at(&self) -> Option<DateTime<Utc>> {
+        self.hidden.first().map(|(deadline, _)| *deadline)
+    }
+
+    fn expired(&self, now: DateTime<Utc>, max: usize) -> Vec<Uuid> {
+        self.expiries
+            .iter()
+            .take_while(|(expires_at, _)| *expires_at <= now)
+            .take(max)
+            .map(|(_, seq)| self.messages[seq].message.uuid)
+            .collect()
+    }
+
+    fn delete(&mut self, uuid: &Uuid) -> io::Result<Option<Message>> {
+        let seq = match self.index.remove(uuid) {
+            None => return Ok(None),
+            Some(seq) => seq,
+        };
+        let entry = self.messages.remove(&seq).unwrap();
+        if !entry.waiting {
+            match entry.deadline {
+                None => self.ready.remove(&(entry.rank, seq)),
+                Some(deadline) => self.hidden.remove(&(deadline, seq)),
+            };
+        }
+        if let Some(expires_at) = entry.message.get_expires_at() {
+            self.expiries.remove(&(expires_at, seq));
+        }
+        if let Some(group_id) = entry.message.get_group_id() {
+            let group = self.groups.get_mut(group_id).unwrap();
+            group.remove(&seq);
+            // the next message of the group takes its place
+            match group.first() {
+                None => {
+                    self.groups.remove(group_id);
+                }
+                Some(&next) if !entry.waiting && self.messages[&next].waiting => {
+                    self.queue_up(next)
+                }
+                Some(_) => (),
+            }
+        }
+        Ok(Some(entry.message))
+    }
+
+    fn count(&self) -> usize {
+        self.messages.len()
+    }
+
+    fn set_priority(&mut self, priority: Option<PriorityPolicy>) {
+        self.priority = priority;
+        self.ready.clear();
+        for (seq, entry) in self.messages.iter_mut() {
+            entry.rank = rank(self.priority.as_ref(), &entry.message);
+            if !entry.waiting && entry.deadline.is_none() {
+                self.ready.insert((entry.rank, *seq));
+            }
+        }
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        *self = MemoryStore::new(self.priority.take());
+        Ok(())
+    }
+
+    fn destroy(&mut self) -> io::Result<()> {
+        self.clear()
+    }
+
+    fn messages(&self) -> Box<dyn Iterator<Item = &Message> + '_> {
+        Box::new(self.messages.values().map(|e| &e.message))
+    }
+
+    fn is_durable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use aes_gcm::aead::Nonce;
+    use aes_gcm::Aes256Gcm;
+
+    use super::*;
+
+    fn message(id: &str) -> Message {
+        Message::new(
+            id.to_owned(),
+            vec![],
+            Nonce::<Aes256Gcm>::default(),
+            String::from("1"),
+            None,
+            None,
+            None,
+        )
+    }
+
+    fn ids(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|m| m.get_id()).collect()
+    }
+
+    #[test]
+    fn lease_hands_out_messages_in_arrival_order_and_hides_them() {
+        let mut store = MemoryStore::new(None);
+        for id in ["1", "2", "3"] {
+            store.push(message(id)).unwrap();
+        }
+
+        let leased = store.lease(2, 30).unwrap();
+        assert_eq!(ids(&leased), ["1", "2"]);
+        assert!(leased.iter().all(|m| m.receive_count == 1 && m.is_leased()));
+        assert_eq!(ids(&store.lease(10, 30).unwrap()), ["3"]);
+        assert!(store.lease(10, 30).unwrap().is_empty());
+        assert_eq!(store.count(), 3);
+    }
+
+    #[test]
+    fn lease_skips_delayed_messages() {
+        let mut store = MemoryStore::new(None);
+        let mut delayed = message("1");
+        delayed.visible_at = Some(Utc::now() + Duration::seconds(60));
+        store.push(delayed).unwrap();
+        store.push(message("2")).unwrap();
+
+        assert_eq!(ids(&store.lease(10, 30).unwrap()), ["2"]);
+        assert!(store.lease(10, 30).unwrap().is_empty());
+    }
+
+    #[test]
+    fn expire_leases_only_returns_messages_whose_deadline_passed() {
+        let mut store = MemoryStore::new(None);
+        store.push(message("1")).unwrap();
+        store.push(message("2")).unwrap();
+        let first = store.lease(1, 10).unwrap().remove(0);
+        let second = store.lease(1, 20).unwrap().remove(0);
+        let (first_deadline, second_deadline) =
+            (first.visible_at.unwrap(), second.visible_at.unwrap());
+
+        store.expire_leases(first_deadline);
+        assert!(store.ready.is_empty());
+        assert_eq!(store.hidden.len(), 2);
+
+        store.expire_leases(first_deadline + Duration::milliseconds(1));
+        assert_eq!(store.ready.len(), 1);
+        assert_eq!(store.hidden.first(), Some(&(second_deadline, 1)));
+        assert_eq!(store.messages[&0].deadline, None);
+
+        store.expire_leases(second_deadline + Duration::milliseconds(1));
+        assert_eq!(store.ready.len(), 2);
+        assert!(store.hidden.is_empty());
+    }
+
+    #[test]
+    fn lease_hands_out_messages_again_once_their_lease_runs_out() {
+        let mut store = MemoryStore::new(None);
+        store.push(message("1")).unwrap();
+        let receipt = store.lease(1, 0).unwrap()[0].receipt;
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        let leased = store.lease(1, 30).unwrap();
+        assert_eq!(ids(&leased), ["1"]);
+        assert_eq!(leased[0].receive_count, 2);
+        assert_ne!(leased[0].receipt, receipt);
+    }
+
+    #[test]
+    fn delete_removes_visible_leased_and_unknown_messages() {
+        let mut store = MemoryStore::new(None);
+        let (first, second) = (message("1"), message("2"));
+        let (first_uuid, second_uuid) = (first.uuid, second.uuid);
+        store.push(first).unwrap();
+        store.push(second).unwrap();
+        store.lease(1, 30).unwrap();
+
+        assert_eq!(store.delete(&first_uuid).unwrap().unwrap().get_id(), "1");
+        assert!(store.hidden.is_empty());
+        assert_eq!(store.delete(&second_uuid).unwrap().unwrap().get_id(), "2");
+        assert!(store.ready.is_empty());
+        assert!(store.delete(&first_uuid).unwrap().is_none());
+        assert!(store.get(&first_uuid).is_none());
+        assert_eq!(store.count(), 0);
+        assert!(store.lease(10, 30).unwrap().is_empty());
+    }
+
+    #[test]
+    fn delete_hands_out_the_next_message_of_the_group() {
+        let mut store = MemoryStore::new(None);
+        for id in ["1", "2"] {
+            let mut grouped = message(id);
+            grouped.group_id = Some(String::from("g"));
+            store.push(grouped).unwrap();
+        }
+
+        let leased = store.lease(10, 30).unwrap();
+        assert_eq!(ids(&leased), ["1"]);
+        assert!(store.lease(10, 30).unwrap().is_empty());
+        store.delete(&leased[0].uuid).unwrap();
+        assert_eq!(ids(&store.lease(10, 30).unwrap()), ["2"]);
+    }
+
+    #[test]
+    fn set_priority_ranks_queued_messages_again() {
+        let mut store = MemoryStore::new(None);
+        for (id, priority) in [("1", 1), ("2", 9), ("3", 5)] {
+            let mut prioritised = message(id);
+            prioritised.priority = priority;
+            store.push(prioritised).unwrap();
+        }
+
+        store.set_priority(Some(PriorityPolicy {
+            max_priority: 5,
+            aging_seconds: None,
+        }));
+        assert_eq!(ids(&store.lease(1, 30).unwrap()), ["2"]);
+        store.set_priority(None);
+        assert_eq!(ids(&store.lease(10, 30).unwrap()), ["1", "3"]);
+    }
+
+    // pushes, leases and deletes `count` messages a batch at a time and
+    // returns how long it took per message
+    fn time_per_message(count: usize) -> std::time::Duration {
+        let mut store = MemoryStore::new(None);
+        let started = Instant::now();
+        for i in 0..count {
+            store.push(message(&i.to_string())).unwrap();
+        }
+        loop {
+            let leased = store.lease(100, 30).unwrap();
+            if leased.is_empty() {
+                break;
+            }
+            for message in leased {
+                store.delete(&message.uuid).unwrap();
+            }
+        }
+        assert_eq!(store.count(), 0);
+        started.elapsed() / count as u32
+    }
+
+    // run with `cargo test --release -- --ignored`. A store that scanned the
+    // queue would take about a hundred times longer per message with a
+    // hundred times the backlog
+    #[test]
+    #[ignore]
+    fn lease_and_delete_scale_to_a_million_messages() {
+        let small = time_per_message(10_000);
+        let large = time_per_message(1_000_000);
+        println!(
+            "per message: {:?} with 10k queued, {:?} with 1M queued",
+            small, large
+        );
+        assert!(large < small * 10);
+    }
+}
@@ -1,9 +1,139 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
+use uuid::Uuid;
+
+use super::queue::store::StorageType;
+use super::queue::{BrowsedMessage, QueueConfig, QueueStats};
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct NewQueueRequest {
     pub read_timeout: u32,
     pub queue_id: String,
-    pub max_batch: u32, 
+    pub max_batch: u32,
+    #[serde(default)]
+    pub storage: StorageType,
+    pub max_receive_count: Option<u32>,
+    pub dead_letter_queue_id: Option<String>,
+    #[serde(default)]
+    pub delay_seconds: u32,
+    pub retention_seconds: Option<u32>,
+    #[serde(default)]
+    pub dead_letter_expired: bool,
+    #[serde(default)]
+    pub fifo: bool,
+    pub deduplication_window_seconds: Option<u32>,
+    #[serde(default)]
+    pub content_based_deduplication: bool,
+    pub max_priority: Option<u8>,
+    pub priority_aging_seconds: Option<u32>,
+}
+
+// settings left out are kept. Settings that can be switched off are cleared
+// by sending null
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateQueueRequest {
+    pub queue_id: String,
+    pub read_timeout: Option<u32>,
+    pub max_batch: Option<u32>,
+    pub delay_seconds: Option<u32>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub retention_seconds: Option<Option<u32>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub max_receive_count: Option<Option<u32>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub dead_letter_queue_id: Option<Option<String>>,
+    pub dead_letter_expired: Option<bool>,
+    pub deduplication_window_seconds: Option<u32>,
+    pub content_based_deduplication: Option<bool>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub max_priority: Option<Option<u8>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub priority_aging_seconds: Option<Option<u32>>,
+}
+
+// tells a field sent as null apart from one left out
+fn nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteQueueRequest {
+    pub queue_id: String,
+    #[serde(default)]
+    pub cascade: bool, // unbind the queue from its exchanges instead of refusing
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueRequest {
+    pub queue_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BrowseQueueRequest {
+    pub queue_id: String,
+    pub offset: Option<usize>, // how many messages to skip, defaults to 0
+    pub limit: Option<usize>,  // the most messages returned
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BrowseQueueResponse {
+    pub messages: Vec<BrowsedMessage>,
+    pub next_offset: Option<usize>, // where the next page starts, if there is one
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueDescription {
+    pub queue_id: String,
+    pub uuid: Uuid,
+    pub read_timeout: u32,
+    pub max_batch: u32,
+    pub storage: StorageType,
+    pub max_receive_count: Option<u32>,
+    pub dead_letter_queue_id: Option<String>,
+    pub delay_seconds: u32,
+    pub retention_seconds: Option<u32>,
+    pub dead_letter_expired: bool,
+    pub fifo: bool,
+    pub deduplication_window_seconds: u32,
+    pub content_based_deduplication: bool,
+    pub max_priority: Option<u8>,
+    pub priority_aging_seconds: Option<u32>,
+    #[serde(flatten)]
+    pub stats: QueueStats,
+}
+
+impl QueueDescription {
+    pub fn new(uuid: Uuid, config: &QueueConfig, stats: QueueStats) -> Self {
+        QueueDescription {
+            queue_id: config.id.to_owned(),
+            uuid,
+            read_timeout: config.read_timeout,
+            max_batch: config.max_batch,
+            storage: config.storage,
+            max_receive_count: config.dead_letter.as_ref().map(|p| p.max_receive_count),
+            dead_letter_queue_id: config
+                .dead_letter
+                .as_ref()
+                .map(|p| p.dead_letter_queue_id.to_owned()),
+            delay_seconds: config.delay_seconds,
+            retention_seconds: config.retention_seconds,
+            dead_letter_expired: config.dead_letter_expired,
+            fifo: config.fifo,
+            deduplication_window_seconds: config.deduplication_window_seconds,
+            content_based_deduplication: config.content_based_deduplication,
+            max_priority: config.priority.as_ref().map(|p| p.max_priority),
+            priority_aging_seconds: config.priority.as_ref().and_then(|p| p.aging_seconds),
+            stats,
+        }
+    }
 }
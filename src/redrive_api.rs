@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{rt, web, HttpResponse};
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::app_types::{AppState, JsonResponse};
+use request::{NewRedriveRequest, RedriveRequest};
+use task::{RedriveFilter, RedriveStatus, RedriveTask};
+
+mod request;
+pub(crate) mod task;
+
+// the most messages moved while holding the locks of both queues
+const BATCH_SIZE: usize = 100;
+// how long a finished redrive can still be looked up
+const FINISHED_RETENTION_SECONDS: i64 = 3600;
+
+pub async fn new_redrive(
+    data: web::Data<AppState>,
+    post_data: web::Json<NewRedriveRequest>,
+) -> HttpResponse {
+    if post_data.source_queue_id == post_data.destination_queue_id {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            "The source and destination queues must be different",
+        ));
+    }
+    if post_data.max_messages == Some(0) {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            "The max number of messages to move 0 is invalid",
+        ));
+    }
+    if post_data.messages_per_second == Some(0) {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            "The rate of 0 messages per second is invalid",
+        ));
+    }
+    {
+        let queues = data.get_queues().read().await;
+        for queue_id in [&post_data.source_queue_id, &post_data.destination_queue_id] {
+            if !queues.contains_key(queue_id) {
+                return HttpResponse::BadRequest().json(JsonResponse::new(
+                    None::<String>,
+                    format!("No queue with id {} was found", queue_id),
+                ));
+            }
+        }
+    }
+
+    let task = Arc::new(RedriveTask::new(
+        post_data.source_queue_id.to_owned(),
+        post_data.destination_queue_id.to_owned(),
+        RedriveFilter {
+            message_ids: post_data.message_ids.clone(),
+            dead_lettered_from: post_data.dead_lettered_from.clone(),
+        },
+        post_data.max_messages,
+        post_data.messages_per_second,
+    ));
+    let redrive_id = task.id.to_string();
+    {
+        let mut redrives = data.get_redrives().lock().await;
+        forget_finished(&mut redrives);
+        redrives.insert(redrive_id.to_owned(), task.clone());
+    }
+    rt::spawn(run(data.clone(), task));
+    HttpResponse::Accepted().json(JsonResponse::new(redrive_id, None::<String>))
+}
+
+pub async fn get_redrive(
+    data: web::Data<AppState>,
+    query_data: web::Query<RedriveRequest>,
+) -> HttpResponse {
+    let mut redrives = data.get_redrives().lock().await;
+    forget_finished(&mut redrives);
+    match redrives.get(&query_data.redrive_id) {
+        None => HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!("No redrive with id {} was found", query_data.redrive_id),
+        )),
+        Some(task) => {
+            HttpResponse::Accepted().json(JsonResponse::new(task.describe(), None::<String>))
+        }
+    }
+}
+
+pub async fn list_redrives(data: web::Data<AppState>) -> HttpResponse {
+    let mut redrives = data.get_redrives().lock().await;
+    forget_finished(&mut redrives);
+    let mut tasks = redrives.values().collect::<Vec<&Arc<RedriveTask>>>();
+    tasks.sort_by_key(|t| t.started_at);
+    let descriptions = tasks.iter().map(|t| t.describe()).collect::<Vec<_>>();
+    HttpResponse::Accepted().json(JsonResponse::new(descriptions, None::<String>))
+}
+
+pub async fn cancel_redrive(
+    data: web::Data<AppState>,
+    post_data: web::Json<RedriveRequest>,
+) -> HttpResponse {
+    let redrives = data.get_redrives().lock().await;
+    let task = match redrives.get(&post_data.redrive_id) {
+        None => {
+            return HttpResponse::BadRequest().json(JsonResponse::new(
+                None::<String>,
+                format!("No redrive with id {} was found", post_data.redrive_id),
+            ))
+        }
+        Some(t) => t,
+    };
+    if task.get_progress().status != RedriveStatus::RUNNING {
+        return HttpResponse::BadRequest().json(JsonResponse::new(
+            None::<String>,
+            format!("The redrive {} has already finished", post_data.redrive_id),
+        ));
+    }
+    task.cancel();
+    HttpResponse::Accepted().json(JsonResponse::new(
+        format!("Cancelling redrive {}", post_data.redrive_id),
+        None::<String>,
+    ))
+}
+
+// drops the redrives that finished more than FINISHED_RETENTION_SECONDS ago,
+// so the redrives kept around do not grow for as long as the service runs
+fn forget_finished(redrives: &mut HashMap<String, Arc<RedriveTask>>) {
+    let cutoff = Utc::now() - chrono::Duration::seconds(FINISHED_RETENTION_SECONDS);
+    redrives.retain(|_, task| !task.finished_before(cutoff));
+}
+
+// moves the messages that were in the source queue when the redrive started,
+// a batch at a time so producers and consumers are never locked out for long
+async fn run(data: web::Data<AppState>, task: Arc<RedriveTask>) {
+    let candidates = match data.get_queue(&task.source_queue_id).await {
+        None => {
+            task.finish(
+                RedriveStatus::FAILED,
+                Some(format!(
+                    "No queue with id {} was found",
+                    task.source_queue_id
+                )),
+            );
+            return;
+        }
+        Some(q) => q
+            .lock()
+            .await
+            .get_messages()
+            .map(|m| m.get_raw_uuid())
+            .collect::<Vec<Uuid>>(),
+    };
+    task.set_total(candidates.len());
+
+    let batch_size = match task.messages_per_second {
+        None => BATCH_SIZE,
+        Some(rate) => (rate as usize).min(BATCH_SIZE),
+    };
+    let mut total_moved = 0;
+    for batch in candidates.chunks(batch_size) {
+        if task.is_cancelled() {
+            task.finish(RedriveStatus::CANCELLED, None);
+            return;
+        }
+        if task.max_messages.is_some_and(|max| total_moved >= max) {
+            break;
+        }
+        let (mut source, mut destination) = match data
+            .lock_queue_pair(&task.source_queue_id, &task.destination_queue_id)
+            .await
+        {
+            None => {
+                task.finish(
+                    RedriveStatus::FAILED,
+                    Some(String::from(
+                        "The source or destination queue no longer exists",
+                    )),
+                );
+                return;
+            }
+            Some(q) => q,
+        };
+        let (mut moved, mut skipped) = (0, 0);
+        for uuid in batch {
+            if task
+                .max_messages
+                .is_some_and(|max| total_moved + moved >= max)
+            {
+                break;
+            }
+            let matches = source
+                .get_message(uuid)
+                .is_some_and(|m| task.filter.matches(m));
+            if !matches {
+                skipped += 1;
+                continue;
+            }
+            match source.redrive_message(data.get_wal(), uuid, &mut destination) {
+                Ok(true) => moved += 1,
+                Ok(false) => skipped += 1,
+                Err(_) => {
+                    task.record(moved, skipped);
+                    task.finish(
+                        RedriveStatus::FAILED,
+                        Some(String::from("Something went wrong. Please try again.")),
+                    );
+                    return;
+                }
+            }
+        }
+        drop((source, destination));
+        task.record(moved, skipped);
+        total_moved += moved;
+
+        match task.messages_per_second {
+            Some(rate) if moved > 0 => {
+                rt::time::sleep(Duration::from_secs_f64(moved as f64 / rate as f64)).await
+            }
+            _ => tokio::task::yield_now().await,
+        }
+    }
+    task.finish(RedriveStatus::COMPLETED, None);
+}
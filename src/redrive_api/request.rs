@@ -0,0 +1,20 @@
+use std::collections::HashSet;
+
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewRedriveRequest {
+    pub source_queue_id: String,
+    pub destination_queue_id: String,
+    pub message_ids: Option<HashSet<String>>,
+    pub dead_lettered_from: Option<String>,
+    pub max_messages: Option<usize>,
+    pub messages_per_second: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RedriveRequest {
+    pub redrive_id: String,
+}
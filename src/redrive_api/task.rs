@@ -0,0 +1,163 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::queue_api::queue::Message;
+
+#[allow(clippy::upper_case_acronyms)] // the variant names are the wire format
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum RedriveStatus {
+    RUNNING,
+    COMPLETED,
+    CANCELLED,
+    FAILED,
+}
+
+// which messages of the source queue are moved. Everything is moved when
+// neither is set
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RedriveFilter {
+    pub message_ids: Option<HashSet<String>>, // only messages with one of these ids
+    pub dead_lettered_from: Option<String>,   // only messages dead-lettered from this queue
+}
+
+impl RedriveFilter {
+    pub fn matches(&self, message: &Message) -> bool {
+        if let Some(message_ids) = &self.message_ids {
+            if !message_ids.contains(message.get_id()) {
+                return false;
+            }
+        }
+        match &self.dead_lettered_from {
+            None => true,
+            Some(queue_id) => message
+                .get_dead_letter()
+                .is_some_and(|d| &d.source_queue_id == queue_id),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RedriveProgress {
+    pub status: RedriveStatus,
+    pub total: usize,   // messages in the source queue when the redrive started
+    pub moved: usize,   // messages moved to the destination queue so far
+    pub skipped: usize, // messages left behind by the filter, gone or held by a consumer
+    pub error: Option<String>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+// a redrive running in the background. It is shared between the task doing
+// the work and the endpoints reporting on it
+pub struct RedriveTask {
+    pub id: Uuid,
+    pub source_queue_id: String,
+    pub destination_queue_id: String,
+    pub filter: RedriveFilter,
+    pub max_messages: Option<usize>, // stop after moving this many messages
+    pub messages_per_second: Option<u32>, // the most messages moved per second
+    pub started_at: DateTime<Utc>,
+    progress: Mutex<RedriveProgress>,
+    cancelled: AtomicBool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RedriveDescription {
+    id: String,
+    source_queue_id: String,
+    destination_queue_id: String,
+    filter: RedriveFilter,
+    max_messages: Option<usize>,
+    messages_per_second: Option<u32>,
+    started_at: DateTime<Utc>,
+    #[serde(flatten)]
+    progress: RedriveProgress,
+}
+
+impl RedriveTask {
+    pub fn new(
+        source_queue_id: String,
+        destination_queue_id: String,
+        filter: RedriveFilter,
+        max_messages: Option<usize>,
+        messages_per_second: Option<u32>,
+    ) -> Self {
+        RedriveTask {
+            id: Uuid::new_v4(),
+            source_queue_id,
+            destination_queue_id,
+            filter,
+            max_messages,
+            messages_per_second,
+            started_at: Utc::now(),
+            progress: Mutex::new(RedriveProgress {
+                status: RedriveStatus::RUNNING,
+                total: 0,
+                moved: 0,
+                skipped: 0,
+                error: None,
+                finished_at: None,
+            }),
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn get_progress(&self) -> RedriveProgress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    pub fn describe(&self) -> RedriveDescription {
+        RedriveDescription {
+            id: self.id.to_string(),
+            source_queue_id: self.source_queue_id.to_owned(),
+            destination_queue_id: self.destination_queue_id.to_owned(),
+            filter: self.filter.clone(),
+            max_messages: self.max_messages,
+            messages_per_second: self.messages_per_second,
+            started_at: self.started_at,
+            progress: self.get_progress(),
+        }
+    }
+
+    // asks the task to stop after the batch it is working on
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_total(&self, total: usize) {
+        self.progress.lock().unwrap().total = total;
+    }
+
+    pub fn record(&self, moved: usize, skipped: usize) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.moved += moved;
+        progress.skipped += skipped;
+    }
+
+    // whether the task completed, was cancelled or failed before `cutoff`
+    pub fn finished_before(&self, cutoff: DateTime<Utc>) -> bool {
+        self.progress
+            .lock()
+            .unwrap()
+            .finished_at
+            .is_some_and(|dt| dt < cutoff)
+    }
+
+    pub fn finish(&self, status: RedriveStatus, error: Option<String>) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.status = status;
+        progress.error = error;
+        progress.finished_at = Some(Utc::now());
+    }
+}